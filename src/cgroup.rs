@@ -0,0 +1,279 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    process, thread,
+    time::Duration,
+};
+
+use crate::output::number;
+
+const CONTROLLERS: &[&str] = &["cpu", "memory", "io", "pids"];
+
+/// Finds the cgroup v2 directory that sirun itself is in.
+#[cfg(target_os = "linux")]
+fn own_cgroup() -> Result<PathBuf, String> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup")
+        .map_err(|err| format!("unable to read /proc/self/cgroup: {}", err))?;
+    let cgroup = cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or("not in a cgroup v2 hierarchy")?;
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")
+        .map_err(|err| format!("unable to read /proc/self/mountinfo: {}", err))?;
+    // Each line is `id parent dev root mount_point options... - type source
+    // super_options`.
+    let (root, mount_point) = mountinfo
+        .lines()
+        .find_map(|line| {
+            let (mount, fs) = line.split_once(" - ")?;
+            if fs.split(' ').next()? != "cgroup2" {
+                return None;
+            }
+            let mut fields = mount.split(' ').skip(3);
+            Some((fields.next()?, fields.next()?))
+        })
+        .ok_or("no cgroup v2 filesystem is mounted")?;
+    let relative = cgroup
+        .strip_prefix(root)
+        .ok_or("sirun's cgroup isn't visible in the cgroup v2 mount")?;
+    Ok(Path::new(mount_point).join(relative.trim_start_matches('/')))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn own_cgroup() -> Result<PathBuf, String> {
+    Err("cgroups are only supported on Linux".into())
+}
+
+/// The controllers that are enabled for the children of `path`.
+fn enabled_controllers(path: &Path) -> Vec<String> {
+    fs::read_to_string(path.join("cgroup.subtree_control"))
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Enables whichever of the controllers sirun reads from are available to the
+/// children of `path`, returning those that weren't already enabled. This is
+/// allowed to fail, in which case the files for the missing controllers just
+/// won't exist.
+fn enable_controllers(path: &Path) -> Vec<&'static str> {
+    let available = fs::read_to_string(path.join("cgroup.controllers")).unwrap_or_default();
+    let enabled = enabled_controllers(path);
+    CONTROLLERS
+        .iter()
+        .copied()
+        .filter(|controller| available.split_whitespace().any(|name| name == *controller))
+        .filter(|controller| !enabled.iter().any(|name| name == controller))
+        .filter(|controller| {
+            let subtree_control = path.join("cgroup.subtree_control");
+            fs::write(subtree_control, format!("+{}", controller)).is_ok()
+        })
+        .collect()
+}
+
+fn disable_controllers(path: &Path, controllers: &[&str]) {
+    for controller in controllers {
+        let _ = fs::write(
+            path.join("cgroup.subtree_control"),
+            format!("-{}", controller),
+        );
+    }
+}
+
+/// A cgroup below sirun's own one, holding a leaf cgroup for each run of a
+/// variant.
+pub(crate) struct Cgroups {
+    parent: PathBuf,
+    path: PathBuf,
+    runs: usize,
+    /// Whether sirun moved itself out of `parent`.
+    moved: bool,
+    /// The controllers that sirun enabled for the children of `parent`.
+    enabled: Vec<&'static str>,
+    missing: Vec<&'static str>,
+}
+
+impl Cgroups {
+    pub(crate) fn create() -> Result<Cgroups, String> {
+        let parent = own_cgroup()?;
+        let path = parent.join(format!("sirun-{}", process::id()));
+        fs::create_dir(&path)
+            .map_err(|err| format!("unable to create {}: {}", path.display(), err))?;
+        // Controllers can only be enabled for a cgroup's children while it
+        // has no processes of its own, other than at the root, so sirun moves
+        // itself into a leaf of its own first. Anything it runs outside of
+        // the `run` command ends up there too.
+        let own = path.join("sirun");
+        let moved = fs::create_dir(&own)
+            .and_then(|()| fs::write(own.join("cgroup.procs"), process::id().to_string()))
+            .is_ok();
+        let enabled = enable_controllers(&parent);
+        enable_controllers(&path);
+        let enabled_for_runs = enabled_controllers(&path);
+        let missing = CONTROLLERS
+            .iter()
+            .copied()
+            .filter(|controller| !enabled_for_runs.iter().any(|name| name == controller))
+            .collect();
+        Ok(Cgroups {
+            parent,
+            path,
+            runs: 0,
+            moved,
+            enabled,
+            missing,
+        })
+    }
+
+    /// The controllers that couldn't be enabled for the runs, whose metrics
+    /// are left out.
+    pub(crate) fn missing_controllers(&self) -> &[&'static str] {
+        &self.missing
+    }
+
+    /// Creates an empty leaf cgroup for a single run.
+    pub(crate) fn create_leaf(&mut self) -> io::Result<Cgroup> {
+        self.runs += 1;
+        let path = self.path.join(format!("run-{}", self.runs));
+        fs::create_dir(&path)?;
+        Ok(Cgroup { path })
+    }
+}
+
+impl Drop for Cgroups {
+    fn drop(&mut self) {
+        // sirun can only move back into its own cgroup once that has no
+        // controllers enabled for its children again.
+        disable_controllers(&self.path, CONTROLLERS);
+        disable_controllers(&self.parent, &self.enabled);
+        if self.moved {
+            let procs = self.parent.join("cgroup.procs");
+            if let Err(err) = fs::write(procs, process::id().to_string()) {
+                eprintln!(
+                    "Unable to move sirun back to cgroup {}: {}",
+                    self.parent.display(),
+                    err
+                );
+            }
+        }
+        let _ = fs::remove_dir(self.path.join("sirun"));
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+/// A leaf cgroup containing a single run and all of its descendants, however
+/// they were started. It's killed and removed when dropped.
+pub(crate) struct Cgroup {
+    path: PathBuf,
+}
+
+fn read_flat_keyed(path: &Path) -> HashMap<String, f64> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(' ')?;
+            Some((key.to_owned(), value.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+impl Cgroup {
+    /// The file that a process's pid can be written to in order to move it
+    /// into this cgroup.
+    pub(crate) fn procs_path(&self) -> PathBuf {
+        self.path.join("cgroup.procs")
+    }
+
+    /// Adds the accounting for everything that ran in this cgroup to
+    /// `metrics`, as `cgroup.*` metrics. Anything belonging to a controller
+    /// that isn't enabled is left out.
+    pub(crate) fn get_metrics(&self, metrics: &mut Map<String, Value>) {
+        let cpu = read_flat_keyed(&self.path.join("cpu.stat"));
+        for (key, name) in &[
+            ("usage_usec", "cgroup.cpu.usage"),
+            ("user_usec", "cgroup.cpu.user"),
+            ("system_usec", "cgroup.cpu.system"),
+        ] {
+            if let Some(value) = cpu.get(*key) {
+                metrics.insert(name.to_string(), number(*value));
+            }
+        }
+
+        for (file, name) in &[
+            ("memory.peak", "cgroup.memory.peak"),
+            ("pids.peak", "cgroup.pids.peak"),
+        ] {
+            let value = fs::read_to_string(self.path.join(file))
+                .ok()
+                .and_then(|value| value.trim().parse().ok());
+            if let Some(value) = value {
+                metrics.insert(name.to_string(), number(value));
+            }
+        }
+
+        let memory = read_flat_keyed(&self.path.join("memory.stat"));
+        for key in &["pgfault", "pgmajfault"] {
+            if let Some(value) = memory.get(*key) {
+                metrics.insert(format!("cgroup.memory.{}", key), number(*value));
+            }
+        }
+
+        // Each line of `io.stat` is a device followed by `key=value` pairs, and
+        // the totals across all devices are reported.
+        if let Ok(io) = fs::read_to_string(self.path.join("io.stat")) {
+            let mut totals: HashMap<&str, f64> = HashMap::new();
+            let pairs = io
+                .split_whitespace()
+                .filter_map(|pair| pair.split_once('='));
+            for (key, value) in pairs {
+                *totals.entry(key).or_default() += value.parse::<f64>().unwrap_or(0.0);
+            }
+            for key in &["rbytes", "wbytes", "rios", "wios"] {
+                let value = totals.get(key).copied().unwrap_or(0.0);
+                metrics.insert(format!("cgroup.io.{}", key), number(value));
+            }
+        }
+    }
+
+    /// Kills anything left in the cgroup, such as daemonized descendants that
+    /// weren't waited for.
+    fn kill(&self) {
+        if fs::write(self.path.join("cgroup.kill"), "1").is_ok() {
+            return;
+        }
+        // `cgroup.kill` is only available from Linux 5.14.
+        let procs = fs::read_to_string(self.procs_path()).unwrap_or_default();
+        for pid in procs.lines().filter_map(|pid| pid.parse().ok()) {
+            let _ = kill(Pid::from_raw(pid), Signal::SIGKILL);
+        }
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        self.kill();
+        // The cgroup can only be removed once the killed processes are gone.
+        for _ in 0..100 {
+            match fs::remove_dir(&self.path) {
+                Err(err) if err.raw_os_error() == Some(nix::libc::EBUSY) => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                _ => return,
+            }
+        }
+        eprintln!("Unable to remove cgroup {}", self.path.display());
+    }
+}
@@ -0,0 +1,110 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use std::{env, path::PathBuf};
+use structopt::{clap::AppSettings, StructOpt};
+
+use crate::config::Format;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    about = "Takes basic performance measurements of a process covering its entire lifetime.",
+    settings = &[AppSettings::ArgsNegateSubcommands, AppSettings::ArgRequiredElseHelp],
+)]
+pub(crate) struct Cli {
+    #[structopt(flatten)]
+    pub(crate) run: RunOpts,
+
+    #[structopt(subcommand)]
+    pub(crate) command: Option<Subcommand>,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) enum Subcommand {
+    /// Runs the benchmark described by a config file (the default).
+    Run(RunOpts),
+    /// Prints the commands and environment each selected variant would be run with, without running anything.
+    Explain(RunOpts),
+    /// Checks that a config file and its variants are valid, without running anything, reporting every problem found.
+    Validate(ConfigOpts),
+    /// Lists the keys of the variants in a config file.
+    ListVariants(ConfigOpts),
+    /// Prints the JSON Schema that config files follow.
+    Schema,
+}
+
+/// How config files are read.
+#[derive(StructOpt, Debug)]
+pub(crate) struct LoadOpts {
+    /// The format of the config file: json, yaml or toml. Defaults to the one matching its extension.
+    #[structopt(long, env = "SIRUN_CONFIG_FORMAT")]
+    pub(crate) format: Option<Format>,
+
+    /// Warns about keys that aren't part of the config format, instead of rejecting the config.
+    #[structopt(long)]
+    pub(crate) warn_unknown_keys: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct ConfigOpts {
+    /// The config file describing the benchmark.
+    #[structopt(parse(from_os_str))]
+    pub(crate) config: PathBuf,
+
+    #[structopt(flatten)]
+    pub(crate) load: LoadOpts,
+
+    /// Variant keys or glob patterns, separated by commas. Defaults to all of them.
+    #[structopt(long, env = "SIRUN_VARIANT")]
+    pub(crate) variant: Option<String>,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct RunOpts {
+    /// The config file describing the benchmark.
+    #[structopt(parse(from_os_str))]
+    pub(crate) config: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    pub(crate) load: LoadOpts,
+
+    /// Variant keys or glob patterns to run, separated by commas. Use '*' to run all of them.
+    #[structopt(long, env = "SIRUN_VARIANT")]
+    pub(crate) variant: Option<String>,
+
+    /// A name to include in the results.
+    #[structopt(long, env = "SIRUN_NAME")]
+    pub(crate) name: Option<String>,
+
+    /// A version (typically a commit hash) to include in the results.
+    #[structopt(long, env = "GIT_COMMIT_HASH")]
+    pub(crate) commit_hash: Option<String>,
+
+    /// Overrides the timeout in the config, in seconds.
+    #[structopt(long, env = "SIRUN_TIMEOUT")]
+    pub(crate) timeout: Option<u64>,
+
+    /// Appends results to this file instead of writing them to stdout.
+    #[structopt(long, env = "SIRUN_OUTPUT", parse(from_os_str))]
+    pub(crate) output: Option<PathBuf>,
+
+    /// Overrides the address to listen on for Statsd messages.
+    #[structopt(long, env = "SIRUN_STATSD_ADDRESS")]
+    pub(crate) statsd_address: Option<String>,
+
+    /// Skips running the setup command. Also enabled by setting SIRUN_SKIP_SETUP.
+    #[structopt(long)]
+    pub(crate) skip_setup: bool,
+
+    /// Prints what would be run instead of running it, like `sirun explain`.
+    #[structopt(long)]
+    pub(crate) dry_run: bool,
+}
+
+impl RunOpts {
+    pub(crate) fn skip_setup(&self) -> bool {
+        self.skip_setup || env::var("SIRUN_SKIP_SETUP").is_ok()
+    }
+}
@@ -3,158 +3,1809 @@
 //
 // This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
 
-use serde_json::{from_str, Value};
-use std::convert::{TryFrom, TryInto};
-use std::{collections::HashMap, env, fs::read_to_string};
+use serde::{
+    de::{
+        self, value::MapAccessDeserializer, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess,
+        Visitor,
+    },
+    Deserialize, Deserializer,
+};
+use serde_json::Value;
+use serde_path_to_error::Segment;
+use std::convert::TryFrom;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    fs::read_to_string,
+    io,
+    marker::PhantomData,
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
+use crate::cli::LoadOpts;
+
+#[derive(Clone)]
 pub(crate) struct Config {
-    pub(crate) setup: Option<Vec<String>>,
+    pub(crate) variant: Option<String>,
+    pub(crate) setup: Option<Setup>,
+    pub(crate) probes: Vec<Probe>,
+    pub(crate) services: Vec<Service>,
+    pub(crate) teardown: Option<Teardown>,
     pub(crate) run: Vec<String>,
+    /// When there are any, `run` is the server they drive, keyed by name.
+    pub(crate) load: Vec<(String, Vec<String>)>,
     pub(crate) timeout: Option<u64>,
+    pub(crate) grace_period: Duration,
+    pub(crate) iterations: u64,
+    pub(crate) warmup: u64,
+    pub(crate) statsd_address: String,
+    pub(crate) cgroup: bool,
+    pub(crate) sample_interval: Option<Duration>,
     pub(crate) env: HashMap<String, String>,
+    /// The keys in `env` that the variant set, rather than the base config.
+    pub(crate) variant_env: HashSet<String>,
+}
+
+/// The `setup` command, and how to retry it until it succeeds.
+#[derive(Clone)]
+pub(crate) struct Setup {
+    pub(crate) command: Vec<String>,
+    pub(crate) attempts: u64,
+    pub(crate) interval: Duration,
+    pub(crate) backoff: f64,
+    pub(crate) max_interval: Duration,
+    pub(crate) attempt_timeout: Option<Duration>,
+    pub(crate) deadline: Option<Duration>,
+}
+
+/// The `teardown` command, and how long it can run for.
+#[derive(Clone)]
+pub(crate) struct Teardown {
+    pub(crate) command: Vec<String>,
+    pub(crate) timeout: Duration,
+}
+
+const DEFAULT_TEARDOWN_TIMEOUT: u64 = 60;
+
+impl Default for Teardown {
+    fn default() -> Teardown {
+        Teardown {
+            command: Vec::new(),
+            timeout: Duration::from_secs(DEFAULT_TEARDOWN_TIMEOUT),
+        }
+    }
+}
+
+/// What a readiness probe checks for.
+#[derive(Clone)]
+pub(crate) enum Check {
+    /// A TCP connection can be made to the address.
+    Tcp(String),
+    /// A `GET` request for `path` from the server at `address` gets a
+    /// response with `status`.
+    Http {
+        address: String,
+        host: String,
+        path: String,
+        status: u16,
+    },
+    /// The file exists.
+    File(PathBuf),
+    /// The Unix socket exists.
+    Socket(PathBuf),
+    /// The command exits with status code 0.
+    Command(Vec<String>),
 }
 
+/// A check that's made repeatedly until it passes or `timeout` is reached.
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "RawProbe")]
+pub(crate) struct Probe {
+    pub(crate) check: Check,
+    pub(crate) timeout: Duration,
+    pub(crate) interval: Duration,
+}
+
+/// A command that's kept running in the background, in its own process group,
+/// while the `run` command is measured.
+#[derive(Clone, Deserialize)]
+#[serde(from = "RawService")]
+pub(crate) struct Service {
+    pub(crate) name: String,
+    pub(crate) command: Vec<String>,
+    pub(crate) probe: Option<Probe>,
+    pub(crate) grace_period: Duration,
+}
+
+const DEFAULT_PROBE_TIMEOUT: u64 = 30;
+const DEFAULT_PROBE_INTERVAL_MS: u64 = 500;
+
+const DEFAULT_STATSD_ADDRESS: &str = "127.0.0.1:8125";
+const DEFAULT_GRACE_PERIOD: u64 = 5;
+const DEFAULT_SETUP_ATTEMPTS: u64 = 100;
+const DEFAULT_SETUP_INTERVAL: u64 = 1;
+const DEFAULT_SETUP_MAX_INTERVAL: u64 = 60;
+
+impl Default for Setup {
+    fn default() -> Setup {
+        Setup {
+            command: Vec::new(),
+            attempts: DEFAULT_SETUP_ATTEMPTS,
+            interval: Duration::from_secs(DEFAULT_SETUP_INTERVAL),
+            backoff: 1.0,
+            max_interval: Duration::from_secs(DEFAULT_SETUP_MAX_INTERVAL),
+            attempt_timeout: None,
+            deadline: None,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
 struct ProtoConfig {
-    setup: Option<Vec<String>>,
+    setup: Option<Setup>,
+    probes: Vec<Probe>,
+    services: Vec<Service>,
+    teardown: Option<Teardown>,
     run: Option<Vec<String>>,
+    load: Vec<(String, Vec<String>)>,
     timeout: Option<u64>,
+    grace_period: Option<Duration>,
+    iterations: Option<u64>,
+    warmup: Option<u64>,
+    statsd_address: Option<String>,
+    cgroup: Option<bool>,
+    sample_interval: Option<Duration>,
     env: HashMap<String, String>,
 }
 
-#[derive(Debug, Clone)]
-pub(crate) struct ConfigError(String);
-
 impl TryFrom<ProtoConfig> for Config {
-    type Error = ConfigError;
+    type Error = String;
 
-    fn try_from(config: ProtoConfig) -> Result<Config, ConfigError> {
+    fn try_from(config: ProtoConfig) -> Result<Config, String> {
+        if let Some(setup) = &config.setup {
+            if setup.command.is_empty() {
+                return Err("'setup' must include a 'command'".into());
+            }
+        }
+        if let Some(teardown) = &config.teardown {
+            if teardown.command.is_empty() {
+                return Err("'teardown' must include a 'command'".into());
+            }
+        }
         Ok(Config {
+            variant: None,
             setup: config.setup,
+            probes: config.probes,
+            services: config.services,
+            teardown: config.teardown,
             run: match config.run {
                 Some(run) => run,
                 None => return Err("'run' must be provided".into()),
             },
+            load: config.load,
             timeout: config.timeout,
+            grace_period: config
+                .grace_period
+                .unwrap_or_else(|| Duration::from_secs(DEFAULT_GRACE_PERIOD)),
+            iterations: config.iterations.unwrap_or(1),
+            warmup: config.warmup.unwrap_or(0),
+            statsd_address: config
+                .statsd_address
+                .unwrap_or_else(|| DEFAULT_STATSD_ADDRESS.into()),
+            cgroup: config.cgroup.unwrap_or(false),
+            sample_interval: config.sample_interval,
             env: config.env,
+            variant_env: HashSet::new(),
         })
     }
 }
 
-impl From<String> for ConfigError {
-    fn from(string: String) -> Self {
-        ConfigError(string)
+/// Why the configs couldn't be loaded. `path` is always the config file the
+/// problem is in, and `pointer` is the JSON pointer to the value in it that's
+/// at fault, which is empty when it's the file as a whole.
+#[derive(Debug)]
+pub(crate) enum ConfigError {
+    /// A config file couldn't be read.
+    Io {
+        path: String,
+        extended_by: Option<String>,
+        source: io::Error,
+    },
+    /// A config file isn't well formed, or has a value of the wrong type.
+    Parse {
+        path: String,
+        pointer: String,
+        /// The 1-based line and column, when the format reports them.
+        location: Option<(usize, usize)>,
+        message: String,
+    },
+    /// A config file can be parsed, but doesn't describe a benchmark that can
+    /// be run.
+    Validation {
+        path: String,
+        pointer: String,
+        message: String,
+    },
+    /// The variants to load don't match the ones in the config.
+    Selection(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (path, pointer, message) = match self {
+            ConfigError::Io {
+                path,
+                extended_by,
+                source,
+            } => {
+                write!(f, "unable to read {}", path)?;
+                if let Some(extender) = extended_by {
+                    write!(f, " (extended by {})", extender)?;
+                }
+                return write!(f, ": {}", source);
+            }
+            ConfigError::Parse {
+                path,
+                pointer,
+                location,
+                message,
+            } => {
+                write!(f, "{}", path)?;
+                if let Some((line, column)) = location {
+                    write!(f, ":{}:{}", line, column)?;
+                }
+                ("", pointer, message)
+            }
+            ConfigError::Validation {
+                path,
+                pointer,
+                message,
+            } => (path.as_str(), pointer, message),
+            ConfigError::Selection(message) => return write!(f, "{}", message),
+        };
+        write!(f, "{}: {}", path, message)?;
+        if !pointer.is_empty() {
+            write!(f, " (at {})", pointer)?;
+        }
+        Ok(())
     }
 }
 
-impl From<&str> for ConfigError {
-    fn from(string: &str) -> Self {
-        string.to_owned().into()
+impl ConfigError {
+    /// The file and JSON pointer that the problem is in, if it's in a file.
+    fn location(&self) -> Option<(&str, &str)> {
+        match self {
+            ConfigError::Parse { path, pointer, .. }
+            | ConfigError::Validation { path, pointer, .. } => Some((path, pointer)),
+            _ => None,
+        }
     }
 }
 
-impl From<std::io::Error> for ConfigError {
-    fn from(err: std::io::Error) -> Self {
-        format!("{}", err).into()
+impl std::error::Error for ConfigError {}
+
+/// The JSON Schema describing config files, for editors to check them against.
+/// It has to be kept in step with the types they're deserialized into.
+pub(crate) const SCHEMA: &str = include_str!("../sirun.schema.json");
+
+/// The formats a config file can be written in. Whichever is used, it's read
+/// into the same structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Format, String> {
+        match format.to_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "toml" => Ok(Format::Toml),
+            _ => Err(format!(
+                "unknown config format '{}' (expected json, yaml or toml)",
+                format
+            )),
+        }
     }
 }
 
-impl From<std::env::VarError> for ConfigError {
-    fn from(err: std::env::VarError) -> Self {
-        format!("{}", err).into()
+impl Format {
+    /// Picks the format from the file extension, falling back to JSON.
+    fn detect(filename: &Path) -> Format {
+        filename
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| extension.parse().ok())
+            .unwrap_or(Format::Json)
     }
+
+    /// Parses the config file at `path`, adding the JSON pointer to each key
+    /// in it that isn't part of the config format to `unknown`.
+    fn parse(
+        self,
+        path: &str,
+        contents: &str,
+        unknown: &mut Vec<String>,
+    ) -> Result<Layer, ConfigError> {
+        self.parse_seed(path, contents, PhantomData, unknown)
+    }
+
+    /// Parses the variant or matrix value found by following `keys` from the
+    /// top of the config file at `path`, ignoring the rest of the file, so
+    /// that its problems are reported with their location in the file.
+    fn parse_overlay(
+        self,
+        path: &str,
+        contents: &str,
+        keys: &[String],
+        unknown: &mut Vec<String>,
+    ) -> Result<Layer, ConfigError> {
+        let mut ignored = Vec::new();
+        let seed = Within {
+            keys,
+            marker: PhantomData,
+        };
+        let layer = self.parse_seed(path, contents, seed, &mut ignored);
+        let pointer: String = keys
+            .iter()
+            .map(|key| format!("/{}", escape_key(key)))
+            .collect();
+        // Everything outside the overlay is skipped, which counts as being
+        // ignored too, so only keep the keys within it.
+        unknown.extend(
+            ignored
+                .into_iter()
+                .filter(|ignored| ignored.starts_with(&format!("{}/", pointer))),
+        );
+        layer
+    }
+
+    fn parse_seed<'de, S: DeserializeSeed<'de>>(
+        self,
+        path: &str,
+        contents: &'de str,
+        seed: S,
+        unknown: &mut Vec<String>,
+    ) -> Result<S::Value, ConfigError> {
+        match self {
+            Format::Json => {
+                let mut de = serde_json::Deserializer::from_str(contents);
+                let value = deserialize(&mut de, seed, path, unknown)?;
+                de.end()
+                    .map_err(|err| parse_error(path, String::new(), err))?;
+                Ok(value)
+            }
+            Format::Yaml => deserialize(
+                serde_yaml::Deserializer::from_str(contents),
+                seed,
+                path,
+                unknown,
+            ),
+            Format::Toml => {
+                deserialize(&mut toml::Deserializer::new(contents), seed, path, unknown)
+            }
+        }
+    }
+}
+
+/// An error from one of the formats' parsers, which each report where in the
+/// file it happened in their own way.
+trait Located: fmt::Display {
+    /// The 1-based line and column of the error, if known.
+    fn line_column(&self) -> Option<(usize, usize)>;
+
+    /// The error without the location appended to it, which is reported
+    /// separately.
+    fn message(&self) -> String {
+        let message = self.to_string();
+        match message.rfind(" at line ") {
+            Some(end) if self.line_column().is_some() => message[..end].to_owned(),
+            _ => message,
+        }
+    }
+}
+
+impl Located for serde_json::Error {
+    fn line_column(&self) -> Option<(usize, usize)> {
+        Some((self.line(), self.column())).filter(|(line, _)| *line > 0)
+    }
+}
+
+impl Located for serde_yaml::Error {
+    fn line_column(&self) -> Option<(usize, usize)> {
+        self.location()
+            .map(|location| (location.line(), location.column()))
+    }
+
+    fn message(&self) -> String {
+        let message = self.to_string();
+        let message = match message.rfind(" at line ") {
+            Some(end) if self.line_column().is_some() => &message[..end],
+            _ => &message,
+        };
+        // Errors about a particular value start with its dotted path, which
+        // is reported as a pointer instead.
+        match message.split_once(": ") {
+            Some((path, rest)) if !path.contains(' ') => rest.to_owned(),
+            _ => message.to_owned(),
+        }
+    }
+}
+
+impl Located for toml::de::Error {
+    fn line_column(&self) -> Option<(usize, usize)> {
+        self.line_col().map(|(line, column)| (line + 1, column + 1))
+    }
+
+    fn message(&self) -> String {
+        let message = self.to_string();
+        match message
+            .find(" for key `")
+            .or_else(|| message.rfind(" at line "))
+        {
+            Some(end) if self.line_column().is_some() => message[..end].to_owned(),
+            _ => message,
+        }
+    }
+}
+
+fn parse_error(path: &str, pointer: String, err: impl Located) -> ConfigError {
+    ConfigError::Parse {
+        path: path.to_owned(),
+        pointer,
+        location: err.line_column(),
+        message: err.message(),
+    }
+}
+
+/// Escapes a key for use in a JSON pointer.
+fn escape_key(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// Converts the path to a value that couldn't be deserialized into a JSON
+/// pointer.
+fn error_pointer(path: &serde_path_to_error::Path) -> String {
+    path.iter()
+        .map(|segment| match segment {
+            Segment::Seq { index } => format!("/{}", index),
+            Segment::Map { key } => format!("/{}", escape_key(key)),
+            Segment::Enum { variant } => format!("/{}", escape_key(variant)),
+            Segment::Unknown => "/?".into(),
+        })
+        .collect()
 }
 
-impl From<serde_json::Error> for ConfigError {
-    fn from(err: serde_json::Error) -> Self {
-        format!("{}", err).into()
+/// Converts the path to a key that was ignored into a JSON pointer.
+fn ignored_pointer(path: &serde_ignored::Path) -> String {
+    match path {
+        serde_ignored::Path::Root => String::new(),
+        serde_ignored::Path::Seq { parent, index } => {
+            format!("{}/{}", ignored_pointer(parent), index)
+        }
+        serde_ignored::Path::Map { parent, key } => {
+            format!("{}/{}", ignored_pointer(parent), escape_key(key))
+        }
+        serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => ignored_pointer(parent),
     }
 }
 
-macro_rules! errify {
-    ($format:expr, $val:expr) => {
-        return Err(format!($format, $val).into())
+/// Deserializes the config file at `path` with `seed`, adding the JSON pointer
+/// to each key in it that isn't part of the config format to `unknown`.
+fn deserialize<'de, D, S>(
+    de: D,
+    seed: S,
+    path: &str,
+    unknown: &mut Vec<String>,
+) -> Result<S::Value, ConfigError>
+where
+    D: Deserializer<'de>,
+    D::Error: Located,
+    S: DeserializeSeed<'de>,
+{
+    let mut ignore = |ignored: serde_ignored::Path| unknown.push(ignored_pointer(&ignored));
+    let mut track = serde_path_to_error::Track::new();
+    let de = serde_ignored::Deserializer::new(de, &mut ignore);
+    seed.deserialize(serde_path_to_error::Deserializer::new(de, &mut track))
+        .map_err(|err| parse_error(path, error_pointer(&track.path()), err))
+}
+
+/// Deserializes the `T` found by following `keys` down from the top of a
+/// document, where each key is an object's key or an array's index, and skips
+/// everything else.
+struct Within<'a, T> {
+    keys: &'a [String],
+    marker: PhantomData<T>,
+}
+
+impl<T> Clone for Within<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Within<'_, T> {}
+
+impl<'de, 'a, T: Deserialize<'de>> DeserializeSeed<'de> for Within<'a, T> {
+    type Value = T;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<T, D::Error> {
+        match self.keys.split_first() {
+            None => T::deserialize(deserializer),
+            Some((key, keys)) => deserializer.deserialize_any(WithinVisitor {
+                key,
+                within: Within {
+                    keys,
+                    marker: self.marker,
+                },
+            }),
+        }
+    }
+}
+
+struct WithinVisitor<'a, T> {
+    key: &'a str,
+    within: Within<'a, T>,
+}
+
+impl<'de, 'a, T: Deserialize<'de>> Visitor<'de> for WithinVisitor<'a, T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "an object or array containing '{}'", self.key)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<T, A::Error> {
+        let mut found = None;
+        for i in 0.. {
+            if i.to_string() == self.key {
+                match seq.next_element_seed(self.within)? {
+                    Some(value) => found = Some(value),
+                    None => break,
+                }
+            } else if seq.next_element::<IgnoredAny>()?.is_none() {
+                break;
+            }
+        }
+        found.ok_or_else(|| de::Error::custom(format!("missing index {}", self.key)))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<T, A::Error> {
+        let mut found = None;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == self.key {
+                found = Some(map.next_value_seed(self.within)?);
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        found.ok_or_else(|| de::Error::custom(format!("missing key '{}'", self.key)))
+    }
+}
+
+/// A config file, or a variant's overlay on one, as it's written. Anything
+/// that's left out is inherited.
+#[derive(Deserialize)]
+#[serde(expecting = "a config object")]
+struct Layer {
+    extends: Option<Names>,
+    run: Option<ShellCommand>,
+    load: Option<LoadCommands>,
+    setup: Option<CommandOr<SetupLayer>>,
+    teardown: Option<CommandOr<TeardownLayer>>,
+    probes: Option<Vec<Probe>>,
+    services: Option<Services>,
+    timeout: Option<u64>,
+    grace_period: Option<Seconds>,
+    iterations: Option<NonZeroU64>,
+    warmup: Option<u64>,
+    statsd_address: Option<String>,
+    cgroup: Option<bool>,
+    sample_interval: Option<Interval>,
+    env: Option<HashMap<String, String>>,
+    variants: Option<Keyed<Overlay>>,
+    matrix: Option<Matrix>,
+}
+
+/// A variant's or matrix value's overlay. These are skipped over along with
+/// the rest of the file, and only deserialized afterwards, each on its own, so
+/// that a problem in one of them doesn't stop the others from being checked.
+enum Overlay {
+    Unparsed,
+    Parsed(Box<Layer>),
+    /// It couldn't be deserialized, and the problem has been reported.
+    Invalid,
+}
+
+impl<'de> Deserialize<'de> for Overlay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Read as a plain value, so that none of its keys are reported as
+        // unknown until it's deserialized properly.
+        Value::deserialize(deserializer).map(|_| Overlay::Unparsed)
+    }
+}
+
+impl Overlay {
+    /// Deserializes the overlay found by following `keys` in the config file
+    /// at `path`, adding any problem with it to `errors`.
+    fn parse(
+        &mut self,
+        format: Format,
+        path: &str,
+        contents: &str,
+        keys: &[String],
+        unknown: &mut Vec<String>,
+        errors: &mut Vec<ConfigError>,
+    ) {
+        if let Overlay::Unparsed = self {
+            *self = match format.parse_overlay(path, contents, keys, unknown) {
+                Ok(layer) => Overlay::Parsed(Box::new(layer)),
+                Err(err) => {
+                    errors.push(err);
+                    Overlay::Invalid
+                }
+            };
+        }
+    }
+
+    /// The overlay's layer, unless it couldn't be deserialized.
+    fn layer(&self) -> Option<&Layer> {
+        match self {
+            Overlay::Parsed(layer) => Some(layer),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SetupLayer {
+    command: Option<ShellCommand>,
+    attempts: Option<NonZeroU64>,
+    interval: Option<Seconds>,
+    backoff: Option<Backoff>,
+    max_interval: Option<Seconds>,
+    attempt_timeout: Option<Seconds>,
+    deadline: Option<Seconds>,
+}
+
+#[derive(Deserialize)]
+struct TeardownLayer {
+    command: Option<ShellCommand>,
+    timeout: Option<Seconds>,
+}
+
+#[derive(Deserialize)]
+struct Matrix {
+    dimensions: BTreeMap<String, Keyed<Overlay>>,
+    #[serde(default)]
+    exclude: Vec<BTreeMap<String, ValueKey>>,
+    #[serde(default)]
+    include: Vec<BTreeMap<String, ValueKey>>,
+}
+
+#[derive(Deserialize)]
+struct RawProbe {
+    tcp: Option<String>,
+    http: Option<String>,
+    status: Option<u16>,
+    file: Option<PathBuf>,
+    socket: Option<PathBuf>,
+    command: Option<ShellCommand>,
+    timeout: Option<Seconds>,
+    interval: Option<Interval>,
+}
+
+#[derive(Deserialize)]
+struct RawService {
+    name: Name,
+    command: ShellCommand,
+    probe: Option<Probe>,
+    grace_period: Option<Seconds>,
+}
+
+/// A shell command, split into its arguments.
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+struct ShellCommand(Vec<String>);
+
+impl TryFrom<String> for ShellCommand {
+    type Error = String;
+
+    fn try_from(command: String) -> Result<ShellCommand, String> {
+        shlex::split(&command)
+            .filter(|command| !command.is_empty())
+            .map(ShellCommand)
+            .ok_or_else(|| format!("'{}' is not a properly formed shell command", command))
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(try_from = "f64")]
+struct Seconds(Duration);
+
+impl TryFrom<f64> for Seconds {
+    type Error = &'static str;
+
+    fn try_from(secs: f64) -> Result<Seconds, &'static str> {
+        Duration::try_from_secs_f64(secs)
+            .map(Seconds)
+            .map_err(|_| "expected a non-negative number of seconds")
+    }
+}
+
+/// A number of seconds that's greater than 0.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(try_from = "f64")]
+struct Interval(Duration);
+
+impl TryFrom<f64> for Interval {
+    type Error = &'static str;
+
+    fn try_from(secs: f64) -> Result<Interval, &'static str> {
+        Duration::try_from_secs_f64(secs)
+            .ok()
+            .filter(|interval| !interval.is_zero())
+            .map(Interval)
+            .ok_or("expected a number of seconds greater than 0")
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(try_from = "f64")]
+struct Backoff(f64);
+
+impl TryFrom<f64> for Backoff {
+    type Error = &'static str;
+
+    fn try_from(backoff: f64) -> Result<Backoff, &'static str> {
+        if backoff >= 1.0 && backoff.is_finite() {
+            Ok(Backoff(backoff))
+        } else {
+            Err("expected a number no less than 1")
+        }
+    }
+}
+
+/// The name of a service or load command, which appears in metric names.
+#[derive(Deserialize)]
+#[serde(try_from = "String")]
+struct Name(String);
+
+impl TryFrom<String> for Name {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Name, String> {
+        if name.is_empty() || name.contains('.') {
+            return Err(format!("name '{}' must not be empty or contain '.'", name));
+        }
+        Ok(Name(name))
+    }
+}
+
+impl From<Name> for String {
+    fn from(name: Name) -> String {
+        name.0
+    }
+}
+
+/// Splits an `http://` URL into the address to connect to, the host to send
+/// in the request, and the path to request.
+fn parse_http_url(url: &str) -> Result<(String, String, String), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or("probe URLs must start with 'http://'")?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(format!("probe URL '{}' must include a host", url));
+    }
+    let has_port = host
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+    let address = if has_port {
+        host.to_owned()
+    } else {
+        format!("{}:80", host)
     };
+    Ok((address, host.to_owned(), path.to_owned()))
 }
 
-fn get_shell_command(
-    obj: &serde_json::Map<String, Value>,
-    name: &str,
-) -> Result<Vec<String>, ConfigError> {
-    let run = obj
-        .get(name)
-        .unwrap()
-        .as_str()
-        .ok_or(format!("'{}' must be a string", name))?;
+impl TryFrom<RawProbe> for Probe {
+    type Error = String;
 
-    shlex::split(run)
-        .ok_or_else(|| format!("'{}' must be a properly formed shell command", name).into())
+    fn try_from(probe: RawProbe) -> Result<Probe, String> {
+        let mut checks = Vec::new();
+        if let Some(address) = probe.tcp {
+            checks.push(Check::Tcp(address));
+        }
+        if let Some(url) = probe.http {
+            let (address, host, path) = parse_http_url(&url)?;
+            checks.push(Check::Http {
+                address,
+                host,
+                path,
+                status: probe.status.unwrap_or(200),
+            });
+        }
+        if let Some(path) = probe.file {
+            checks.push(Check::File(path));
+        }
+        if let Some(path) = probe.socket {
+            checks.push(Check::Socket(path));
+        }
+        if let Some(command) = probe.command {
+            checks.push(Check::Command(command.0));
+        }
+        if checks.len() != 1 {
+            return Err(
+                "each probe must have exactly one of 'tcp', 'http', 'file', 'socket' or 'command'"
+                    .into(),
+            );
+        }
+        Ok(Probe {
+            check: checks.remove(0),
+            timeout: probe
+                .timeout
+                .map_or(Duration::from_secs(DEFAULT_PROBE_TIMEOUT), |timeout| {
+                    timeout.0
+                }),
+            interval: probe.interval.map_or(
+                Duration::from_millis(DEFAULT_PROBE_INTERVAL_MS),
+                |interval| interval.0,
+            ),
+        })
+    }
 }
 
-fn get_env(env: &mut HashMap<String, String>, config_env: &Value) -> Result<(), ConfigError> {
-    let config_env = config_env.as_object().ok_or("env must be an object")?;
-    for (name, value) in config_env.iter() {
-        let value = value.as_str().ok_or("env vars must be strings")?;
-        env.insert(name.clone(), value.to_owned());
+impl From<RawService> for Service {
+    fn from(service: RawService) -> Service {
+        Service {
+            name: service.name.into(),
+            command: service.command.0,
+            probe: service.probe,
+            grace_period: service
+                .grace_period
+                .map_or(Duration::from_secs(DEFAULT_GRACE_PERIOD), |grace_period| {
+                    grace_period.0
+                }),
+        }
     }
-    Ok(())
 }
 
-fn apply_config(config: &mut ProtoConfig, config_val: &Value) -> Result<(), ConfigError> {
-    let config_val = config_val.as_object().ok_or("invalid json")?;
+#[derive(Deserialize)]
+#[serde(try_from = "Vec<Service>")]
+struct Services(Vec<Service>);
+
+impl TryFrom<Vec<Service>> for Services {
+    type Error = String;
 
-    if config_val.contains_key("run") {
-        config.run = Some(get_shell_command(&config_val, "run")?);
+    fn try_from(services: Vec<Service>) -> Result<Services, String> {
+        for (i, service) in services.iter().enumerate() {
+            if services[..i].iter().any(|other| other.name == service.name) {
+                return Err(format!(
+                    "service '{}' is defined more than once",
+                    service.name
+                ));
+            }
+        }
+        Ok(Services(services))
     }
+}
 
-    if config_val.contains_key("setup") {
-        config.setup = Some(get_shell_command(&config_val, "setup")?);
+/// Reads the entries of an array, keyed by index.
+fn visit_indexed<'de, T, A>(mut seq: A) -> Result<Vec<(String, T)>, A::Error>
+where
+    T: Deserialize<'de>,
+    A: SeqAccess<'de>,
+{
+    let mut entries = Vec::new();
+    while let Some(entry) = seq.next_element()? {
+        entries.push((entries.len().to_string(), entry));
     }
+    Ok(entries)
+}
 
-    if let Some(timeout_val) = config_val.get("timeout") {
-        config.timeout = Some(
-            timeout_val
-                .as_u64()
-                .ok_or("'timeout' must be a positive integer")?,
-        );
+/// Reads the entries of an object, in order of their keys.
+fn visit_keyed<'de, K, T, A>(mut map: A) -> Result<Vec<(String, T)>, A::Error>
+where
+    K: Deserialize<'de> + Into<String>,
+    T: Deserialize<'de>,
+    A: MapAccess<'de>,
+{
+    let mut entries = BTreeMap::new();
+    while let Some((key, entry)) = map.next_entry::<K, T>()? {
+        entries.insert(key.into(), entry);
+    }
+    Ok(entries.into_iter().collect())
+}
+
+/// Entries given as an array (keyed by index) or an object (keyed by key).
+struct Keyed<T>(Vec<(String, T)>);
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Keyed<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct KeyedVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>> Visitor<'de> for KeyedVisitor<T> {
+            type Value = Keyed<T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an array or an object")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Keyed<T>, A::Error> {
+                visit_indexed(seq).map(Keyed)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Keyed<T>, A::Error> {
+                visit_keyed::<String, _, _>(map).map(Keyed)
+            }
+        }
+
+        deserializer.deserialize_any(KeyedVisitor(PhantomData))
     }
+}
+
+/// The `load` commands, given as a single command or as an array or object of
+/// them.
+struct LoadCommands(Vec<(String, Vec<String>)>);
 
-    if let Some(env) = config_val.get("env") {
-        get_env(&mut config.env, &env)?;
+impl<'de> Deserialize<'de> for LoadCommands {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct LoadVisitor;
+
+        fn commands(entries: Vec<(String, ShellCommand)>) -> LoadCommands {
+            LoadCommands(
+                entries
+                    .into_iter()
+                    .map(|(name, command)| (name, command.0))
+                    .collect(),
+            )
+        }
+
+        impl<'de> Visitor<'de> for LoadVisitor {
+            type Value = LoadCommands;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a shell command, or an array or object of them")
+            }
+
+            fn visit_str<E: de::Error>(self, command: &str) -> Result<LoadCommands, E> {
+                let command = ShellCommand::try_from(command.to_owned()).map_err(E::custom)?;
+                Ok(commands(vec![("0".into(), command)]))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<LoadCommands, A::Error> {
+                visit_indexed(seq).map(commands)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<LoadCommands, A::Error> {
+                visit_keyed::<Name, _, _>(map).map(commands)
+            }
+        }
+
+        deserializer.deserialize_any(LoadVisitor)
     }
-    Ok(())
 }
 
-pub(crate) fn get_config(filename: String) -> Result<Config, ConfigError> {
-    let mut config = ProtoConfig {
-        setup: None,
-        run: None,
-        timeout: None,
-        env: HashMap::new(),
+/// A `setup` or `teardown` command given either as a string, which leaves any
+/// other settings as they were, or as an object with the command and settings.
+enum CommandOr<T> {
+    Command(ShellCommand),
+    Settings(T),
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for CommandOr<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct CommandOrVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>> Visitor<'de> for CommandOrVisitor<T> {
+            type Value = CommandOr<T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a shell command or an object")
+            }
+
+            fn visit_str<E: de::Error>(self, command: &str) -> Result<CommandOr<T>, E> {
+                ShellCommand::try_from(command.to_owned())
+                    .map(CommandOr::Command)
+                    .map_err(E::custom)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<CommandOr<T>, A::Error> {
+                T::deserialize(MapAccessDeserializer::new(map)).map(CommandOr::Settings)
+            }
+        }
+
+        deserializer.deserialize_any(CommandOrVisitor(PhantomData))
+    }
+}
+
+/// The value of an `extends` key, which can be a single name or an array of
+/// them.
+struct Names(Vec<String>);
+
+impl<'de> Deserialize<'de> for Names {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NamesVisitor;
+
+        impl<'de> Visitor<'de> for NamesVisitor {
+            type Value = Names;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string or an array of strings")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<Names, E> {
+                Ok(Names(vec![name.to_owned()]))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Names, A::Error> {
+                let mut names = Vec::new();
+                while let Some(name) = seq.next_element()? {
+                    names.push(name);
+                }
+                Ok(Names(names))
+            }
+        }
+
+        deserializer.deserialize_any(NamesVisitor)
+    }
+}
+
+/// A matrix dimension's value in an `exclude` or `include` entry, given as
+/// its key or index.
+struct ValueKey(String);
+
+impl<'de> Deserialize<'de> for ValueKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ValueKeyVisitor;
+
+        impl<'de> Visitor<'de> for ValueKeyVisitor {
+            type Value = ValueKey;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string or an index")
+            }
+
+            fn visit_str<E: de::Error>(self, key: &str) -> Result<ValueKey, E> {
+                Ok(ValueKey(key.to_owned()))
+            }
+
+            fn visit_u64<E: de::Error>(self, index: u64) -> Result<ValueKey, E> {
+                Ok(ValueKey(index.to_string()))
+            }
+
+            fn visit_i64<E: de::Error>(self, index: i64) -> Result<ValueKey, E> {
+                Ok(ValueKey(index.to_string()))
+            }
+        }
+
+        deserializer.deserialize_any(ValueKeyVisitor)
+    }
+}
+
+impl SetupLayer {
+    fn apply(&self, setup: &mut Setup) {
+        if let Some(command) = &self.command {
+            setup.command = command.0.clone();
+        }
+        if let Some(attempts) = self.attempts {
+            setup.attempts = attempts.get();
+        }
+        if let Some(interval) = self.interval {
+            setup.interval = interval.0;
+        }
+        if let Some(backoff) = self.backoff {
+            setup.backoff = backoff.0;
+        }
+        if let Some(max_interval) = self.max_interval {
+            setup.max_interval = max_interval.0;
+        }
+        if let Some(attempt_timeout) = self.attempt_timeout {
+            setup.attempt_timeout = Some(attempt_timeout.0);
+        }
+        if let Some(deadline) = self.deadline {
+            setup.deadline = Some(deadline.0);
+        }
+    }
+}
+
+impl TeardownLayer {
+    fn apply(&self, teardown: &mut Teardown) {
+        if let Some(command) = &self.command {
+            teardown.command = command.0.clone();
+        }
+        if let Some(timeout) = self.timeout {
+            teardown.timeout = timeout.0;
+        }
+    }
+}
+
+fn apply_layer(config: &mut ProtoConfig, layer: &Layer) {
+    if let Some(run) = &layer.run {
+        config.run = Some(run.0.clone());
+    }
+    if let Some(load) = &layer.load {
+        config.load = load.0.clone();
+    }
+    match &layer.setup {
+        Some(CommandOr::Command(command)) => {
+            config.setup.get_or_insert_with(Setup::default).command = command.0.clone();
+        }
+        Some(CommandOr::Settings(settings)) => {
+            settings.apply(config.setup.get_or_insert_with(Setup::default));
+        }
+        None => {}
+    }
+    match &layer.teardown {
+        Some(CommandOr::Command(command)) => {
+            config
+                .teardown
+                .get_or_insert_with(Teardown::default)
+                .command = command.0.clone();
+        }
+        Some(CommandOr::Settings(settings)) => {
+            settings.apply(config.teardown.get_or_insert_with(Teardown::default));
+        }
+        None => {}
+    }
+    if let Some(probes) = &layer.probes {
+        config.probes = probes.clone();
+    }
+    if let Some(services) = &layer.services {
+        config.services = services.0.clone();
+    }
+    if let Some(timeout) = layer.timeout {
+        config.timeout = Some(timeout);
+    }
+    if let Some(grace_period) = layer.grace_period {
+        config.grace_period = Some(grace_period.0);
+    }
+    if let Some(iterations) = layer.iterations {
+        config.iterations = Some(iterations.get());
+    }
+    if let Some(warmup) = layer.warmup {
+        config.warmup = Some(warmup);
+    }
+    if let Some(statsd_address) = &layer.statsd_address {
+        config.statsd_address = Some(statsd_address.clone());
+    }
+    if let Some(cgroup) = layer.cgroup {
+        config.cgroup = Some(cgroup);
+    }
+    if let Some(sample_interval) = layer.sample_interval {
+        config.sample_interval = Some(sample_interval.0);
+    }
+    if let Some(env) = &layer.env {
+        config.env.extend(env.clone());
+    }
+}
+
+/// Checks that a variant or matrix value doesn't use the keys that only make
+/// sense at the top level of a file.
+fn check_overlay(
+    overlay: &Layer,
+    can_extend: bool,
+    path: &str,
+    pointer: &str,
+) -> Result<(), ConfigError> {
+    let invalid = |key: &str, message: &str| ConfigError::Validation {
+        path: path.to_owned(),
+        pointer: format!("{}/{}", pointer, key),
+        message: message.to_owned(),
     };
-    let json_str = read_to_string(filename)?;
-    let config_val: Value = from_str(&json_str)?;
-
-    apply_config(&mut config, &config_val)?;
-
-    if let Some(variants) = config_val.get("variants") {
-        let variant_key = env::var("SIRUN_VARIANT")?;
-        let config_json;
-        if let Some(variants) = variants.as_array() {
-            let variant_key: usize = variant_key.parse().unwrap();
-            if variants.len() <= variant_key {
-                errify!("variant index {} does not exist in array", variant_key);
-            }
-            config_json = Some(&variants[variant_key]);
-        } else if let Some(variants) = variants.as_object() {
-            config_json = match variants.get(&variant_key) {
-                Some(val) => Some(val),
-                None => errify!("variant key {} does not exist in object", variant_key),
+    if overlay.variants.is_some() {
+        return Err(invalid(
+            "variants",
+            "variants can't have variants of their own",
+        ));
+    }
+    if overlay.matrix.is_some() {
+        return Err(invalid(
+            "matrix",
+            "variants can't have a matrix of their own",
+        ));
+    }
+    if !can_extend && overlay.extends.is_some() {
+        return Err(invalid("extends", "matrix values can't extend anything"));
+    }
+    Ok(())
+}
+
+/// Matches `text` against a glob `pattern`, where `*` matches any sequence of
+/// characters and `?` matches any single character.
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => (0..=text.len()).any(|i| glob_match(rest, &text[i..])),
+        Some((c, rest)) => match text.split_first() {
+            Some((t, text)) => (*c == '?' || c == t) && glob_match(rest, text),
+            None => false,
+        },
+    }
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+/// Picks the variants named by `selector`, a comma-separated list of variant
+/// keys (or array indices) and glob patterns, in the order of `variants`. That
+/// is the order of an array, but object keys are sorted.
+fn select_variants<T>(
+    variants: Vec<(String, T)>,
+    selector: &str,
+) -> Result<Vec<(String, T)>, ConfigError> {
+    let patterns: Vec<&str> = selector
+        .split(',')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .collect();
+    for pattern in &patterns {
+        if !is_glob(pattern) && !variants.iter().any(|(key, _)| key == pattern) {
+            return Err(ConfigError::Selection(format!(
+                "variant {} does not exist",
+                pattern
+            )));
+        }
+    }
+    let patterns: Vec<Vec<char>> = patterns
+        .iter()
+        .map(|pattern| pattern.chars().collect())
+        .collect();
+    let selected: Vec<_> = variants
+        .into_iter()
+        .filter(|(key, _)| {
+            let key: Vec<char> = key.chars().collect();
+            patterns.iter().any(|pattern| glob_match(pattern, &key))
+        })
+        .collect();
+    if selected.is_empty() {
+        return Err(ConfigError::Selection(format!(
+            "no variants match '{}'",
+            selector
+        )));
+    }
+    Ok(selected)
+}
+
+type Dimension<'a> = (&'a String, &'a [(String, Overlay)]);
+
+/// The overlays to apply for a variant, in order, or `None` if one of them
+/// couldn't be deserialized.
+type Overlays<'a> = Option<Vec<&'a Layer>>;
+
+/// Parses a matrix `exclude` or `include` entry into the index of the chosen
+/// value for each dimension, or `None` where the entry doesn't mention it.
+fn get_combination(
+    entry: &BTreeMap<String, ValueKey>,
+    dimensions: &[Dimension],
+    name: &str,
+    path: &str,
+    pointer: &str,
+) -> Result<Vec<Option<usize>>, ConfigError> {
+    let mut combination = vec![None; dimensions.len()];
+    for (dimension, value) in entry {
+        let invalid = |message: String| ConfigError::Validation {
+            path: path.to_owned(),
+            pointer: format!("{}/{}", pointer, escape_key(dimension)),
+            message,
+        };
+        let index = dimensions
+            .iter()
+            .position(|(name, _)| *name == dimension)
+            .ok_or_else(|| {
+                invalid(format!(
+                    "matrix '{}' refers to unknown dimension '{}'",
+                    name, dimension
+                ))
+            })?;
+        combination[index] = Some(
+            dimensions[index]
+                .1
+                .iter()
+                .position(|(key, _)| *key == value.0)
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "matrix '{}' refers to unknown value '{}' for dimension '{}'",
+                        name, value.0, dimension
+                    ))
+                })?,
+        );
+    }
+    Ok(combination)
+}
+
+/// Expands a `matrix` into the cartesian product of its dimensions, minus any
+/// `exclude`d combinations, plus any `include`d ones. Each resulting variant
+/// is the list of overlays to apply, one per dimension, along with the
+/// pointers to the values they're from. Problems with particular values or
+/// entries are added to `errors`, and those entries are skipped.
+fn expand_matrix<'a>(
+    matrix: &'a Matrix,
+    path: &str,
+    errors: &mut Vec<ConfigError>,
+) -> Result<Vec<(String, Vec<String>, Overlays<'a>)>, ConfigError> {
+    if matrix.dimensions.is_empty() {
+        return Err(ConfigError::Validation {
+            path: path.to_owned(),
+            pointer: "/matrix/dimensions".into(),
+            message: "matrix must have at least one dimension".into(),
+        });
+    }
+    let dimensions: Vec<Dimension> = matrix
+        .dimensions
+        .iter()
+        .map(|(name, values)| (name, values.0.as_slice()))
+        .collect();
+    for (name, values) in &dimensions {
+        for (key, overlay) in values.iter() {
+            let overlay = match overlay.layer() {
+                Some(overlay) => overlay,
+                None => continue,
             };
+            let pointer = format!(
+                "/matrix/dimensions/{}/{}",
+                escape_key(name),
+                escape_key(key)
+            );
+            if let Err(err) = check_overlay(overlay, false, path, &pointer) {
+                errors.push(err);
+            }
+        }
+    }
+    let mut get_combinations = |name: &str, entries: &[BTreeMap<String, ValueKey>]| {
+        entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, entry)| {
+                let pointer = format!("/matrix/{}/{}", name, i);
+                match get_combination(entry, &dimensions, name, path, &pointer) {
+                    Ok(combination) => Some((i, combination)),
+                    Err(err) => {
+                        errors.push(err);
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    let excludes = get_combinations("exclude", &matrix.exclude);
+    let includes = get_combinations("include", &matrix.include);
+
+    let mut combinations: Vec<Vec<usize>> = vec![vec![]];
+    for (_, values) in &dimensions {
+        combinations = combinations
+            .into_iter()
+            .flat_map(|combination| {
+                (0..values.len()).map(move |i| {
+                    let mut combination = combination.clone();
+                    combination.push(i);
+                    combination
+                })
+            })
+            .collect();
+    }
+    combinations.retain(|combination| {
+        !excludes.iter().any(|(_, exclude)| {
+            exclude
+                .iter()
+                .zip(combination)
+                .all(|(excluded, i)| excluded.is_none() || *excluded == Some(*i))
+        })
+    });
+    for (i, include) in includes {
+        let include: Vec<usize> = match include.into_iter().collect() {
+            Some(include) => include,
+            None => {
+                errors.push(ConfigError::Validation {
+                    path: path.to_owned(),
+                    pointer: format!("/matrix/include/{}", i),
+                    message: "matrix 'include' entries must specify every dimension".into(),
+                });
+                continue;
+            }
+        };
+        if !combinations.contains(&include) {
+            combinations.push(include);
+        }
+    }
+
+    Ok(combinations
+        .into_iter()
+        .map(|combination| {
+            let chosen = dimensions
+                .iter()
+                .zip(combination)
+                .map(|((name, values), i)| (name, &values[i]));
+            let key: Vec<String> = chosen
+                .clone()
+                .map(|(name, (value, _))| format!("{}={}", name, value))
+                .collect();
+            let pointers = chosen
+                .clone()
+                .map(|(name, (value, _))| {
+                    format!(
+                        "/matrix/dimensions/{}/{}",
+                        escape_key(name),
+                        escape_key(value)
+                    )
+                })
+                .collect();
+            (
+                key.join("/"),
+                pointers,
+                chosen.map(|(_, (_, overlay))| overlay.layer()).collect(),
+            )
+        })
+        .collect())
+}
+
+fn describe_chain(chain: &[String], last: &str) -> String {
+    let mut chain = chain.to_vec();
+    chain.push(last.to_owned());
+    chain.join(" -> ")
+}
+
+/// Reads a config file, followed by every file it `extends` (relative to its
+/// own directory), adding them all to `layers` in the order they apply, so
+/// that each one overrides those before it. `chain` is the files that led to
+/// this one, which it mustn't be one of. Unknown keys are added to `errors`
+/// rather than stopping the rest being read.
+fn read_layers(
+    filename: &Path,
+    format: Option<Format>,
+    warn_unknown_keys: bool,
+    chain: &mut Vec<(PathBuf, String)>,
+    layers: &mut Vec<(String, Layer)>,
+    errors: &mut Vec<ConfigError>,
+) -> Result<(), ConfigError> {
+    let display = filename.display().to_string();
+    let extended_by = chain.last().map(|(_, parent)| parent.clone());
+    let io_error = |source| ConfigError::Io {
+        path: display.clone(),
+        extended_by: extended_by.clone(),
+        source,
+    };
+    let contents = read_to_string(filename).map_err(io_error)?;
+    let canonical = filename.canonicalize().map_err(io_error)?;
+    if chain.iter().any(|(path, _)| *path == canonical) {
+        let names: Vec<String> = chain.iter().map(|(_, name)| name.clone()).collect();
+        return Err(ConfigError::Validation {
+            path: extended_by.unwrap_or_default(),
+            pointer: "/extends".into(),
+            message: format!(
+                "config files extend each other in a cycle: {}",
+                describe_chain(&names, &display)
+            ),
+        });
+    }
+    let format = format.unwrap_or_else(|| Format::detect(filename));
+    let mut unknown = Vec::new();
+    let mut layer = format.parse(&display, &contents, &mut unknown)?;
+    let mut overlay_errors = Vec::new();
+    if let Some(variants) = &mut layer.variants {
+        for (key, overlay) in &mut variants.0 {
+            let keys = ["variants".to_owned(), key.clone()];
+            overlay.parse(
+                format,
+                &display,
+                &contents,
+                &keys,
+                &mut unknown,
+                &mut overlay_errors,
+            );
+        }
+    }
+    if let Some(matrix) = &mut layer.matrix {
+        for (name, values) in &mut matrix.dimensions {
+            for (key, overlay) in &mut values.0 {
+                let keys = [
+                    "matrix".to_owned(),
+                    "dimensions".to_owned(),
+                    name.clone(),
+                    key.clone(),
+                ];
+                overlay.parse(
+                    format,
+                    &display,
+                    &contents,
+                    &keys,
+                    &mut unknown,
+                    &mut overlay_errors,
+                );
+            }
+        }
+    }
+    for pointer in unknown {
+        let key = pointer
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .replace("~1", "/")
+            .replace("~0", "~");
+        let err = ConfigError::Validation {
+            path: display.clone(),
+            pointer,
+            message: format!("unknown key '{}'", key),
+        };
+        if warn_unknown_keys {
+            eprintln!("warning: {}", err);
         } else {
-            return Err("variants must be array or object".into());
+            errors.push(err);
+        }
+    }
+    errors.append(&mut overlay_errors);
+
+    if let Some(extends) = &layer.extends {
+        let directory = filename.parent().unwrap_or_else(|| Path::new(""));
+        chain.push((canonical, display.clone()));
+        for base in &extends.0 {
+            read_layers(
+                &directory.join(base),
+                None,
+                warn_unknown_keys,
+                chain,
+                layers,
+                errors,
+            )?;
+        }
+        chain.pop();
+    }
+    layers.push((display, layer));
+    Ok(())
+}
+
+/// Lists the overlays to apply for the variant `key`: those of the variants
+/// it `extends`, in order, followed by its own. `chain` is the variants that
+/// led to this one, which it mustn't be one of. The pointers to the variants
+/// that are looked at are added to `used`.
+fn get_variant_overlays<'a>(
+    key: &str,
+    variants: &'a [(String, Overlay)],
+    path: &str,
+    chain: &mut Vec<String>,
+    used: &mut Vec<String>,
+) -> Result<Overlays<'a>, ConfigError> {
+    let invalid = |extender: &str, message: String| ConfigError::Validation {
+        path: path.to_owned(),
+        pointer: format!("/variants/{}/extends", escape_key(extender)),
+        message,
+    };
+    if let Some(extender) = chain.last().filter(|_| chain.iter().any(|k| k == key)) {
+        return Err(invalid(
+            extender,
+            format!(
+                "variants extend each other in a cycle: {}",
+                describe_chain(chain, key)
+            ),
+        ));
+    }
+    let variant = match variants.iter().find(|(variant_key, _)| variant_key == key) {
+        Some((_, variant)) => variant,
+        None => {
+            return Err(match chain.last() {
+                Some(extender) => invalid(
+                    extender,
+                    format!(
+                        "variant {} extends variant {}, which does not exist",
+                        extender, key
+                    ),
+                ),
+                None => ConfigError::Selection(format!("variant {} does not exist", key)),
+            })
+        }
+    };
+    used.push(format!("/variants/{}", escape_key(key)));
+    let variant = match variant.layer() {
+        Some(variant) => variant,
+        None => return Ok(None),
+    };
+    let mut overlays = Vec::new();
+    if let Some(extends) = &variant.extends {
+        chain.push(key.to_owned());
+        for base in &extends.0 {
+            match get_variant_overlays(base, variants, path, chain, used)? {
+                Some(base_overlays) => overlays.extend(base_overlays),
+                None => return Ok(None),
+            }
         }
-        apply_config(&mut config, &config_json.unwrap())?;
+        chain.pop();
+    }
+    overlays.push(variant);
+    Ok(Some(overlays))
+}
+
+/// The pointer to the variant or matrix value that `pointer` is within, if
+/// it's within one.
+fn overlay_pointer(pointer: &str) -> Option<&str> {
+    let depth = if pointer.starts_with("/variants/") {
+        2
+    } else if pointer.starts_with("/matrix/dimensions/") {
+        4
+    } else {
+        return None;
+    };
+    let end = pointer
+        .match_indices('/')
+        .nth(depth)
+        .map_or(pointer.len(), |(i, _)| i);
+    Some(&pointer[..end])
+}
+
+/// Loads the config file, returning one `Config` per selected variant, or just
+/// the base config if there are no variants. Problems that don't stop the rest
+/// of the config being checked are added to `errors`, and the configs they
+/// affect left out.
+fn resolve_configs(
+    filename: &Path,
+    opts: &LoadOpts,
+    variant_selector: Option<&str>,
+    errors: &mut Vec<ConfigError>,
+) -> Result<Vec<Config>, ConfigError> {
+    let mut layers = Vec::new();
+    read_layers(
+        filename,
+        opts.format,
+        opts.warn_unknown_keys,
+        &mut Vec::new(),
+        &mut layers,
+        errors,
+    )?;
+    let mut config = ProtoConfig::default();
+    for (_, layer) in &layers {
+        apply_layer(&mut config, layer);
     }
+    let path = filename.display().to_string();
+    let invalid = |message: String| ConfigError::Validation {
+        path: path.clone(),
+        pointer: String::new(),
+        message,
+    };
+
+    // Variants are inherited as a whole, from whichever file defines them
+    // last.
+    let defining = layers
+        .iter()
+        .rev()
+        .find(|(_, layer)| layer.variants.is_some() || layer.matrix.is_some());
+    // Each variant comes with the pointers to the overlays it uses.
+    type Variant<'a> = (Vec<String>, Result<Overlays<'a>, ConfigError>);
+    let variants: Vec<(String, Variant)> = match defining {
+        Some((
+            path,
+            Layer {
+                variants: Some(_),
+                matrix: Some(_),
+                ..
+            },
+        )) => {
+            return Err(ConfigError::Validation {
+                path: path.clone(),
+                pointer: String::new(),
+                message: "only one of 'variants' or 'matrix' may be used".into(),
+            })
+        }
+        Some((
+            path,
+            Layer {
+                variants: Some(variants),
+                ..
+            },
+        )) => variants
+            .0
+            .iter()
+            .map(|(key, overlay)| {
+                let pointer = format!("/variants/{}", escape_key(key));
+                let mut used = vec![pointer.clone()];
+                let overlays = match overlay.layer() {
+                    Some(overlay) => check_overlay(overlay, true, path, &pointer).and_then(|()| {
+                        get_variant_overlays(key, &variants.0, path, &mut Vec::new(), &mut used)
+                    }),
+                    None => Ok(None),
+                };
+                (key.clone(), (used, overlays))
+            })
+            .collect(),
+        Some((
+            path,
+            Layer {
+                matrix: Some(matrix),
+                ..
+            },
+        )) => expand_matrix(matrix, path, errors)?
+            .into_iter()
+            .map(|(key, pointers, overlays)| (key, (pointers, Ok(overlays))))
+            .collect(),
+        _ => return Ok(vec![Config::try_from(config).map_err(invalid)?]),
+    };
+    let selector = variant_selector.ok_or_else(|| {
+        ConfigError::Selection(
+            "--variant or SIRUN_VARIANT must be set to select from 'variants' or 'matrix' (use '*' for all of them)".into(),
+        )
+    })?;
+    let selected = select_variants(variants, selector)?;
+    // Problems within variants or matrix values that none of the selected
+    // variants use don't stop them being run.
+    let defining_path = defining.map(|(path, _)| path.as_str());
+    let used: HashSet<&str> = selected
+        .iter()
+        .flat_map(|(_, (pointers, _))| pointers.iter().map(String::as_str))
+        .collect();
+    errors.retain(|err| match err.location() {
+        Some((path, pointer)) if Some(path) == defining_path => {
+            overlay_pointer(pointer).is_none_or(|overlay| used.contains(overlay))
+        }
+        _ => true,
+    });
+    Ok(selected
+        .into_iter()
+        .filter_map(|(key, (_, overlays))| {
+            let variant_config = overlays.and_then(|overlays| {
+                // Any overlay that couldn't be deserialized has already been
+                // reported.
+                let overlays = match overlays {
+                    Some(overlays) => overlays,
+                    None => return Ok(None),
+                };
+                let mut variant_config = config.clone();
+                let mut variant_env = HashSet::new();
+                for overlay in overlays {
+                    apply_layer(&mut variant_config, overlay);
+                    if let Some(env) = &overlay.env {
+                        variant_env.extend(env.keys().cloned());
+                    }
+                }
+                let mut variant_config = Config::try_from(variant_config)
+                    .map_err(|message| invalid(format!("variant {}: {}", key, message)))?;
+                variant_config.variant = Some(key);
+                variant_config.variant_env = variant_env;
+                Ok(Some(variant_config))
+            });
+            variant_config
+                .map_err(|err| errors.push(err))
+                .ok()
+                .flatten()
+        })
+        .collect())
+}
+
+/// Loads the config file like `get_configs`, but carries on past as many
+/// problems as it can, returning all of them.
+pub(crate) fn check_configs(
+    filename: &Path,
+    opts: &LoadOpts,
+    variant_selector: Option<&str>,
+) -> Result<Vec<Config>, Vec<ConfigError>> {
+    let mut errors = Vec::new();
+    match resolve_configs(filename, opts, variant_selector, &mut errors) {
+        Ok(configs) if errors.is_empty() => Ok(configs),
+        Ok(_) => Err(errors),
+        Err(err) => {
+            errors.push(err);
+            Err(errors)
+        }
+    }
+}
 
-    Ok(config.try_into()?)
+/// Loads the config file, returning one `Config` per selected variant, or just
+/// the base config if there are no variants.
+pub(crate) fn get_configs(
+    filename: &Path,
+    opts: &LoadOpts,
+    variant_selector: Option<&str>,
+) -> Result<Vec<Config>, ConfigError> {
+    check_configs(filename, opts, variant_selector).map_err(|mut errors| errors.remove(0))
 }
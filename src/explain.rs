@@ -0,0 +1,85 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use serde_json::{json, Map, Value};
+use std::{env, net::ToSocketAddrs};
+
+use crate::{config::Config, statsd::sendable};
+
+fn describe_env(config: &Config) -> Map<String, Value> {
+    let mut described = Map::new();
+    let mut add = |name: &str, value: &str, origin: &str| {
+        described.insert(name.into(), json!({ "value": value, "origin": origin }));
+    };
+    for (name, value) in env::vars() {
+        add(&name, &value, "host");
+    }
+    for (name, value) in &config.env {
+        let origin = if config.variant_env.contains(name) {
+            "variant"
+        } else {
+            "base"
+        };
+        add(name, value, origin);
+    }
+    // These are set by sirun itself once it's listening for Statsd messages,
+    // so resolve the address the same way binding it would.
+    match config
+        .statsd_address
+        .to_socket_addrs()
+        .map(|mut addrs| addrs.next())
+    {
+        Ok(Some(addr)) => {
+            let addr = sendable(addr);
+            let port = match addr.port() {
+                0 => "(assigned at run time)".to_owned(),
+                port => port.to_string(),
+            };
+            add("SIRUN_STATSD_HOST", &addr.ip().to_string(), "sirun");
+            add("SIRUN_STATSD_PORT", &port, "sirun");
+        }
+        _ => {
+            add("SIRUN_STATSD_HOST", &config.statsd_address, "sirun");
+            add("SIRUN_STATSD_PORT", "", "sirun");
+        }
+    }
+    described
+}
+
+/// Describes what would be run for `config`: each command as the arguments
+/// it's split into, and the environment they're run with, along with where
+/// each variable comes from.
+pub(crate) fn explain(config: &Config, skip_setup: bool) -> Value {
+    let setup = config.setup.as_ref().filter(|_| !skip_setup);
+    let mut explained = json!({
+        "setup": setup.map(|setup| &setup.command),
+        "run": config.run,
+        "teardown": config.teardown.as_ref().map(|teardown| &teardown.command),
+        "timeout": config.timeout,
+        "iterations": config.iterations,
+        "warmup": config.warmup,
+        "env": describe_env(config),
+    });
+    if let Some(variant) = &config.variant {
+        explained["variant"] = variant.clone().into();
+    }
+    if !config.load.is_empty() {
+        explained["load"] = config
+            .load
+            .iter()
+            .map(|(name, command)| (name.clone(), json!(command)))
+            .collect::<Map<_, _>>()
+            .into();
+    }
+    if !config.services.is_empty() {
+        explained["services"] = config
+            .services
+            .iter()
+            .map(|service| (service.name.clone(), json!(service.command)))
+            .collect::<Map<_, _>>()
+            .into();
+    }
+    explained
+}
@@ -0,0 +1,176 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use async_std::task;
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    io::Result,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::Config,
+    output::describe_exit,
+    probe::wait_for_probes,
+    process::{interrupted, spawn, Child, Exit, Stop},
+    rusage::get_kernel_metrics,
+};
+
+/// What happened in a single iteration of load mode, other than to the server
+/// itself.
+pub(crate) struct Load {
+    /// Whether the server exited by itself before it was stopped.
+    server_exited: bool,
+    probe_failure: Option<Map<String, Value>>,
+    /// The details of the first `load` command that didn't succeed.
+    failure: Option<Map<String, Value>>,
+    /// How each `load` command ended, keyed by name.
+    pub(crate) results: Map<String, Value>,
+    /// `probes.wall.time`, along with the `load.<name>.*` metrics.
+    pub(crate) metrics: Map<String, Value>,
+}
+
+impl Load {
+    /// Adds the details of whatever went wrong to `failure`, returning the
+    /// status to report for it.
+    pub(crate) fn status(
+        &mut self,
+        server: &Exit,
+        failure: &mut Map<String, Value>,
+    ) -> &'static str {
+        if self.server_exited {
+            eprintln!("Server exited before the load was done, so aborting test.");
+            describe_exit(server.status, failure);
+            "server_exited"
+        } else if let Some(probe_failure) = self.probe_failure.take() {
+            failure.extend(probe_failure);
+            "probe_failed"
+        } else if let Some(load_failure) = self.failure.take() {
+            failure.extend(load_failure);
+            "load_failed"
+        } else {
+            "success"
+        }
+    }
+}
+
+/// Gets metrics from the last line of a `load` command's stdout, if it's a
+/// JSON object, taking whichever of its values are numbers.
+fn get_output_metrics(stdout: &str) -> Map<String, Value> {
+    let last_line = stdout.lines().rev().find(|line| !line.trim().is_empty());
+    match last_line.and_then(|line| serde_json::from_str::<Value>(line).ok()) {
+        Some(Value::Object(object)) => object
+            .into_iter()
+            .filter(|(_, value)| value.is_number())
+            .collect(),
+        _ => Map::new(),
+    }
+}
+
+/// Runs a single iteration against `server`, which has just been started:
+/// waits for the `probes` to pass, runs every `load` command at once until
+/// they've all exited, then stops the server. The `timeout` covers the whole
+/// iteration, and the `load` commands are stopped if it's exceeded or sirun is
+/// interrupted, in which case that's returned too.
+pub(crate) async fn drive(
+    server: Child,
+    config: &Config,
+    env: &HashMap<String, String>,
+) -> Result<(Exit, Option<Stop>, Load)> {
+    let start = Instant::now();
+    let mut stop = None;
+    let mut load = Load {
+        server_exited: false,
+        probe_failure: None,
+        failure: None,
+        results: Map::new(),
+        metrics: Map::new(),
+    };
+    if !config.probes.is_empty() {
+        match wait_for_probes(&config.probes, env).await {
+            Ok(wall_time) => {
+                let wall_time = (wall_time.as_micros() as u64).into();
+                load.metrics.insert("probes.wall.time".into(), wall_time);
+            }
+            Err(_) if interrupted() => stop = Some(Stop::Interrupted),
+            Err(probe_failure) => load.probe_failure = Some(probe_failure),
+        }
+    }
+
+    if stop.is_none() && load.probe_failure.is_none() {
+        let timeout = config
+            .timeout
+            .map(|timeout| Duration::from_secs(timeout).saturating_sub(start.elapsed()));
+        let mut running = Vec::new();
+        for (name, command) in &config.load {
+            match spawn(command, env, None, true) {
+                Ok(child) => {
+                    let exit = task::spawn(child.wait(timeout, config.grace_period));
+                    running.push((name, Instant::now(), exit));
+                }
+                Err(err) => {
+                    eprintln!("Unable to run load command {}: {}", name, err);
+                    let mut result = Map::new();
+                    result.insert("error".into(), err.to_string().into());
+                    result.insert("status".into(), "spawn_failed".into());
+                    load.results.insert(name.clone(), result.into());
+                }
+            }
+        }
+        for (name, load_start, exit) in running {
+            let (exit, load_stop) = match exit.await {
+                Ok(result) => result,
+                Err(err) => {
+                    // It's been killed, and the server still needs stopping.
+                    eprintln!("Error running load command {}: {}", name, err);
+                    let mut result = Map::new();
+                    result.insert("error".into(), err.to_string().into());
+                    result.insert("status".into(), "error".into());
+                    load.results.insert(name.clone(), result.into());
+                    continue;
+                }
+            };
+            let wall_time = load_start.elapsed();
+            stop = stop.or(load_stop);
+
+            let mut result = Map::new();
+            let status = match load_stop {
+                Some(Stop::TimedOut) => "timeout",
+                Some(Stop::Interrupted) => "interrupted",
+                None if exit.status.success() => "success",
+                None => describe_exit(exit.status, &mut result),
+            };
+            if status != "success" {
+                result.insert("stderr".into(), exit.stderr.into());
+            }
+            result.insert("status".into(), status.into());
+            load.results.insert(name.clone(), result.into());
+
+            let mut metrics = Map::new();
+            metrics.insert("wall.time".into(), (wall_time.as_micros() as u64).into());
+            get_kernel_metrics(&mut metrics, &exit.rusage);
+            metrics.extend(get_output_metrics(&exit.stdout));
+            for (metric, value) in metrics {
+                load.metrics
+                    .insert(format!("load.{}.{}", name, metric), value);
+            }
+        }
+        // Reported in the order they're declared in.
+        load.failure = config.load.iter().find_map(|(name, _)| {
+            let result = load.results.get(name)?.as_object()?;
+            if result["status"] == "success" {
+                return None;
+            }
+            let mut failure = result.clone();
+            failure.insert("load".into(), name.clone().into());
+            Some(failure)
+        });
+    }
+
+    let (server, server_exited) = server.stop(config.grace_period).await?;
+    load.server_exited = server_exited;
+    Ok((server, stop, load))
+}
@@ -3,158 +3,618 @@
 //
 // This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
 
-use async_std::{
-    net::UdpSocket,
-    process::Command,
-    sync::{Arc, Barrier, RwLock},
-    task,
-};
-use nix::{
-    libc::{getrusage, RUSAGE_CHILDREN},
-    unistd,
-};
+use async_std::task;
+use serde_json::{json, Map, Value};
 use std::{
     collections::HashMap,
-    env,
-    ffi::{CStr, CString},
-    io::Result,
-    mem,
+    path::Path,
     process::exit,
+    time::{Duration, Instant},
 };
+use structopt::StructOpt;
 
+mod cgroup;
+mod cli;
 mod config;
+mod explain;
+mod load;
+mod output;
+mod probe;
+mod process;
+mod rusage;
+mod sampler;
+mod service;
+mod stats;
+mod statsd;
 
-use config::get_config;
-
-async fn statsd_listener(barrier: Arc<Barrier>, statsd_buf: Arc<RwLock<String>>) -> Result<String> {
-    let socket: UdpSocket = UdpSocket::bind("127.0.0.1:8125").await?;
-    barrier.wait().await; // indicates to main task that socket is listening
+use cgroup::Cgroups;
+use cli::{Cli, ConfigOpts, LoadOpts, RunOpts, Subcommand};
+use config::{check_configs, get_configs, Config, Setup, Teardown, SCHEMA};
+use explain::explain;
+use load::drive;
+use output::{describe_exit, emit, get_metadata, get_units, number};
+use probe::wait_for_probes;
+use process::{
+    become_subreaper, handle_interrupts, interrupt_signal, interrupted, run_unmeasured, spawn, Stop,
+};
+use rusage::get_kernel_metrics;
+use sampler::{Sampler, Series};
+use service::{start_services, stop_services};
+use stats::{aggregate, aggregate_tagged};
+use statsd::{get_statsd_metrics, Statsd, TaggedMetric};
 
-    loop {
-        let mut buf = vec![0u8; 4096];
-        let (recv, _peer) = socket.recv_from(&mut buf).await?;
+/// Runs `setup` until it succeeds, returning how long that took, or the
+/// details of the last failure if it never does.
+async fn run_setup(
+    setup: &Setup,
+    env: &HashMap<String, String>,
+) -> Result<Duration, Map<String, Value>> {
+    let start = Instant::now();
+    let mut interval = setup.interval;
+    let mut failure = Map::new();
+    for attempt in 1..=setup.attempts {
+        if interrupted() {
+            break;
+        }
+        if let Some(deadline) = setup.deadline {
+            if attempt > 1 && start.elapsed() >= deadline {
+                failure.insert("deadline".into(), number(deadline.as_secs_f64()));
+                break;
+            }
+        }
+        failure = Map::new();
+        failure.insert("attempts".into(), attempt.into());
+        // An attempt is cut short by whichever comes first out of its own
+        // timeout and the overall deadline. Each limit is kept along with its
+        // name and configured value, to report if it's hit.
+        let limits = [
+            setup
+                .attempt_timeout
+                .map(|timeout| ("timeout", timeout, timeout)),
+            setup.deadline.map(|deadline| {
+                let remaining = deadline.saturating_sub(start.elapsed());
+                ("deadline", deadline, remaining)
+            }),
+        ];
+        let limit = limits
+            .iter()
+            .flatten()
+            .min_by_key(|(_, _, remaining)| *remaining)
+            .copied();
+        let timeout = limit.map(|(_, _, remaining)| remaining);
+        match run_unmeasured(&setup.command, env, timeout, true).await {
+            Ok((Some(status), _)) if status.success() => return Ok(start.elapsed()),
+            Ok((status, stderr)) => {
+                match status {
+                    Some(status) => {
+                        describe_exit(status, &mut failure);
+                    }
+                    None if interrupted() => {}
+                    None => {
+                        let (name, value, _) = limit.unwrap();
+                        failure.insert(name.into(), number(value.as_secs_f64()));
+                    }
+                }
+                failure.insert("stderr".into(), stderr.into());
+            }
+            Err(err) => {
+                // Retrying won't help if the command can't be run at all.
+                eprintln!("Unable to run setup script: {}", err);
+                failure.insert("error".into(), err.to_string().into());
+                return Err(failure);
+            }
+        }
 
-        let datum = String::from_utf8(buf[..recv].into()).unwrap_or_else(|_| String::new());
-        statsd_buf.write().await.push_str(&datum);
+        if attempt == setup.attempts {
+            break;
+        }
+        let remaining = setup
+            .deadline
+            .map(|deadline| deadline.saturating_sub(start.elapsed()));
+        task::sleep(remaining.map_or(interval, |remaining| interval.min(remaining))).await;
+        let next_interval = interval.as_secs_f64() * setup.backoff;
+        interval = Duration::from_secs_f64(next_interval.min(setup.max_interval.as_secs_f64()));
     }
+    eprintln!("setup script did not complete successfully. aborting.");
+    Err(failure)
 }
 
-fn get_statsd_metrics(metrics: &mut HashMap<String, String>, udp_data: String) {
-    let lines = udp_data.trim().lines();
-    for line in lines {
-        let metric = line.split('|').next();
-        if metric.is_none() {
-            continue;
+/// Runs `teardown`, returning how long it took, along with its status and,
+/// unless it succeeded, the details of what went wrong. It isn't interrupted
+/// when sirun is, since that's when it's most needed.
+async fn run_teardown(
+    teardown: &Teardown,
+    env: &HashMap<String, String>,
+) -> (Duration, Map<String, Value>) {
+    let start = Instant::now();
+    let mut result = Map::new();
+    let timeout = Some(teardown.timeout);
+    let status = match run_unmeasured(&teardown.command, env, timeout, false).await {
+        Ok((Some(status), _)) if status.success() => "success",
+        Ok((Some(status), stderr)) => {
+            result.insert("stderr".into(), stderr.into());
+            describe_exit(status, &mut result)
         }
-        let metric: Vec<&str> = metric.unwrap().split(':').collect();
-        if metric.len() < 2 {
-            continue;
+        Ok((None, stderr)) => {
+            let timeout = number(teardown.timeout.as_secs_f64());
+            result.insert("timeout".into(), timeout);
+            result.insert("stderr".into(), stderr.into());
+            "timeout"
         }
-        metrics.insert(metric[0].into(), metric[1].into());
+        Err(err) => {
+            result.insert("error".into(), err.to_string().into());
+            "spawn_failed"
+        }
+    };
+    if status != "success" {
+        eprintln!("teardown script did not complete successfully.");
     }
+    result.insert("status".into(), status.into());
+    (start.elapsed(), result)
+}
+
+/// The results of a single run of the `run` command. Unless it succeeded, the
+/// details of what went wrong are in `failure`. In load mode, how each `load`
+/// command ended is in `load`. If Statsd metrics may have been missed,
+/// `statsd_complete` is false.
+struct Iteration {
+    metrics: Map<String, Value>,
+    tagged_metrics: Vec<TaggedMetric>,
+    series: Option<Series>,
+    status: &'static str,
+    failure: Map<String, Value>,
+    load: Option<Map<String, Value>>,
+    statsd_complete: bool,
 }
 
-fn get_kernel_metrics(metrics: &mut HashMap<String, String>) {
-    let mut data = unsafe { mem::MaybeUninit::uninit().assume_init() };
-    if unsafe { getrusage(RUSAGE_CHILDREN, &mut data) } == -1 {
-        return;
+async fn run_iteration(
+    config: &Config,
+    env: &HashMap<String, String>,
+    statsd: &Statsd,
+    cgroups: Option<&mut Cgroups>,
+) -> Iteration {
+    let cgroup = match cgroups.map(Cgroups::create_leaf) {
+        Some(Ok(cgroup)) => Some(cgroup),
+        Some(Err(err)) => {
+            eprintln!("Unable to create a cgroup for this run: {}", err);
+            None
+        }
+        None => None,
+    };
+    let start = Instant::now();
+    let child = match spawn(&config.run, env, cgroup.as_ref(), false) {
+        Ok(child) => child,
+        Err(err) => {
+            eprintln!("Unable to run test: {}", err);
+            let mut failure = Map::new();
+            failure.insert("error".into(), err.to_string().into());
+            return Iteration {
+                metrics: Map::new(),
+                tagged_metrics: Vec::new(),
+                series: None,
+                status: "spawn_failed",
+                failure,
+                load: None,
+                statsd_complete: true,
+            };
+        }
+    };
+    let sampler = config
+        .sample_interval
+        .map(|interval| Sampler::start(child.pgid(), interval));
+    let timeout = config.timeout.map(Duration::from_secs);
+    let result = if config.load.is_empty() {
+        let result = child.wait(timeout, config.grace_period).await;
+        result.map(|(exit, stop)| (exit, stop, None))
+    } else {
+        let result = drive(child, config, env).await;
+        result.map(|(exit, stop, load)| (exit, stop, Some(load)))
+    };
+    let wall_time = start.elapsed();
+    let series = match sampler {
+        Some(sampler) => Some(sampler.stop().await),
+        None => None,
+    };
+    let (result, stop, mut load) = match result {
+        Ok(result) => result,
+        Err(err) => {
+            // The child has been killed, so the rest of the variant, such as
+            // its teardown, can still go ahead.
+            eprintln!("Error running test: {}", err);
+            let mut failure = Map::new();
+            failure.insert("error".into(), err.to_string().into());
+            let mut metrics = Map::new();
+            metrics.insert("wall.time".into(), (wall_time.as_micros() as u64).into());
+            return Iteration {
+                metrics,
+                tagged_metrics: Vec::new(),
+                series,
+                status: "error",
+                failure,
+                load: None,
+                statsd_complete: true,
+            };
+        }
+    };
+
+    let mut failure = Map::new();
+    let status = if stop == Some(Stop::TimedOut) {
+        eprintln!("Timeout of {} seconds exceeded.", config.timeout.unwrap());
+        failure.insert("timeout".into(), json!(config.timeout));
+        "timeout"
+    } else if stop == Some(Stop::Interrupted) {
+        eprintln!("Interrupted, so aborting test.");
+        "interrupted"
+    } else if let Some(load) = &mut load {
+        load.status(&result, &mut failure)
+    } else if result.status.success() {
+        "success"
+    } else {
+        match result.status.code() {
+            Some(code) => eprintln!("Test exited with code {}, so aborting test.", code),
+            None => eprintln!("Test was terminated by a signal, so aborting test."),
+        }
+        describe_exit(result.status, &mut failure)
+    };
+    if status != "success" {
+        // A failed `load` command's own stderr is more useful than the
+        // server's.
+        failure
+            .entry("stderr")
+            .or_insert_with(|| result.stderr.clone().into());
+    }
+
+    let mut metrics = Map::new();
+    metrics.insert("wall.time".into(), (wall_time.as_micros() as u64).into());
+    get_kernel_metrics(&mut metrics, &result.rusage);
+    if let Some(cgroup) = &cgroup {
+        cgroup.get_metrics(&mut metrics);
+    }
+    if let Some(series) = &series {
+        series.get_metrics(&mut metrics);
+    }
+    let mut tagged_metrics = Vec::new();
+    let (udp_data, statsd_complete) = statsd.take().await;
+    if !statsd_complete {
+        eprintln!("Timed out waiting for Statsd metrics, so some may be missing.");
+    }
+    get_statsd_metrics(&mut metrics, &mut tagged_metrics, udp_data);
+    if let Some(load) = &mut load {
+        metrics.append(&mut load.metrics);
+    }
+    Iteration {
+        metrics,
+        tagged_metrics,
+        series,
+        status,
+        failure,
+        load: load.map(|load| load.results),
+        statsd_complete,
     }
-    metrics.insert("max.res.size".into(), format!("{}", data.ru_maxrss));
-    metrics.insert("user.time".into(), format!("{}", data.ru_utime.tv_usec));
-    metrics.insert("system.time".into(), format!("{}", data.ru_stime.tv_usec));
 }
 
-async fn run_setup(setup: &[String], env: &HashMap<String, String>) {
-    let mut code: i32 = 1;
-    let mut attempts: u8 = 0;
-    while code != 0 {
-        if attempts == 100 {
-            eprintln!("setup script did not complete successfully. aborting.");
+async fn run_variant(config: &Config, opts: &RunOpts) -> Value {
+    let statsd = match Statsd::start(&config.statsd_address).await {
+        Ok(statsd) => statsd,
+        Err(err) => {
+            eprintln!(
+                "Error listening for Statsd on {}: {}",
+                config.statsd_address, err
+            );
             exit(1);
         }
-        let command = setup[0].clone();
-        let args = setup.iter().skip(1);
-        code = Command::new(command)
-            .args(args)
-            .envs(env.clone())
-            .status()
-            .await
-            .unwrap()
-            .code()
-            .unwrap();
-        if code != 0 {
-            task::sleep(std::time::Duration::from_secs(1)).await;
-            attempts += 1;
-        }
-    }
-
-    // now run in a new process with execvp, skipping setup
-    env::set_var("SIRUN_SKIP_SETUP", "true");
-    let filename = env::args().next().unwrap();
-    let args: Vec<_> = env::args()
-        .map(|s| CString::new(s.as_bytes()).unwrap())
-        .collect();
-    let args: Vec<&CStr> = args.iter().map(|s| s.as_c_str()).collect();
-    let _ = unistd::execvp(CString::new(filename).unwrap().as_c_str(), &args);
-    // This process stops running past here.
-}
+    };
+    let mut env = config.env.clone();
+    env.insert("SIRUN_STATSD_HOST".into(), statsd.addr().ip().to_string());
+    env.insert("SIRUN_STATSD_PORT".into(), statsd.addr().port().to_string());
 
-async fn test_timeout(timeout: u64) {
-    task::sleep(std::time::Duration::from_secs(timeout)).await;
-    eprintln!("Timeout of {} seconds exceeded.", timeout);
-    exit(1);
-}
+    // This comes first, as sirun may need to move itself into a cgroup of its
+    // own, and whatever it's started by then would be left behind.
+    let mut cgroups = None;
+    let mut cgroup_metadata = Map::new();
+    if config.cgroup {
+        match Cgroups::create() {
+            Ok(created) => {
+                cgroup_metadata.insert("enabled".into(), true.into());
+                let missing = created.missing_controllers();
+                if !missing.is_empty() {
+                    eprintln!(
+                        "Unable to enable the {} cgroup controllers, so their metrics are left out.",
+                        missing.join(", ")
+                    );
+                    cgroup_metadata.insert("missing_controllers".into(), missing.into());
+                }
+                cgroups = Some(created);
+            }
+            Err(reason) => {
+                eprintln!("Falling back to measuring without a cgroup: {}", reason);
+                cgroup_metadata.insert("enabled".into(), false.into());
+                cgroup_metadata.insert("reason".into(), reason.into());
+            }
+        }
+    }
 
-#[async_std::main]
-async fn main() {
-    let config = get_config(env::args().nth(1).unwrap());
-    if config.is_err() {
-        eprintln!("{:?}", config.err().unwrap());
-        exit(1);
+    let mut status = "success";
+    let mut failure = Map::new();
+    let mut setup_wall_time = None;
+    if let Some(setup) = &config.setup {
+        if !opts.skip_setup() {
+            match run_setup(setup, &env).await {
+                Ok(wall_time) => setup_wall_time = Some(wall_time),
+                Err(setup_failure) => {
+                    status = if interrupted() {
+                        "interrupted"
+                    } else {
+                        "setup_failed"
+                    };
+                    failure = setup_failure;
+                }
+            }
+        }
     }
-    let config = config.unwrap();
-    if let Some(setup) = config.setup {
-        if env::var("SIRUN_SKIP_SETUP").is_err() {
-            run_setup(&setup, &config.env).await;
+    let mut services = Vec::new();
+    if status == "success" && !config.services.is_empty() {
+        let (started, service_failure) = start_services(&config.services, &env).await;
+        services = started;
+        if let Some(service_failure) = service_failure {
+            status = if interrupted() {
+                "interrupted"
+            } else {
+                "service_failed"
+            };
+            failure = service_failure;
         }
     }
-    let statsd_started = Arc::new(Barrier::new(2));
-    let statsd_buf = Arc::new(RwLock::new(String::new()));
-    task::spawn(statsd_listener(statsd_started.clone(), statsd_buf.clone()));
+    let mut probes_wall_time = None;
+    // In load mode, the probes are for the server, so they're checked once
+    // it's been started in each iteration instead.
+    if status == "success" && !config.probes.is_empty() && config.load.is_empty() {
+        match wait_for_probes(&config.probes, &env).await {
+            Ok(wall_time) => probes_wall_time = Some(wall_time),
+            Err(probe_failure) => {
+                status = if interrupted() {
+                    "interrupted"
+                } else {
+                    "probe_failed"
+                };
+                failure = probe_failure;
+            }
+        }
+    }
+    statsd.take().await; // discards anything sent during setup
 
-    statsd_started.wait().await; // waits for socket to be listening
+    if status == "success" {
+        for _ in 0..config.warmup {
+            if interrupted() {
+                status = "interrupted";
+                break;
+            }
+            let iteration = run_iteration(config, &env, &statsd, cgroups.as_mut()).await;
+            if iteration.status != "success" {
+                status = iteration.status;
+                failure = iteration.failure;
+                break;
+            }
+        }
+    }
+    let mut iterations = Vec::new();
+    let mut tagged_iterations = Vec::new();
+    let mut series = Vec::new();
+    let mut load = None;
+    let mut statsd_complete = true;
+    if status == "success" {
+        for _ in 0..config.iterations {
+            if interrupted() {
+                status = "interrupted";
+                break;
+            }
+            let iteration = run_iteration(config, &env, &statsd, cgroups.as_mut()).await;
+            iterations.push(iteration.metrics);
+            tagged_iterations.push(iteration.tagged_metrics);
+            series.extend(iteration.series.map(|series| series.to_json()));
+            load = iteration.load.or(load);
+            statsd_complete &= iteration.statsd_complete;
+            if iteration.status != "success" {
+                status = iteration.status;
+                failure = iteration.failure;
+                break;
+            }
+        }
+    }
+    statsd.stop().await;
+    let mut service_metrics = Map::new();
+    let (service_results, service_failure) = stop_services(services, &mut service_metrics).await;
+    if let Some(service_failure) = service_failure {
+        // A service that exited early may have affected the results.
+        if status == "success" {
+            status = "service_failed";
+            failure = service_failure;
+        }
+    }
+    let teardown = match &config.teardown {
+        Some(teardown) => Some(run_teardown(teardown, &env).await),
+        None => None,
+    };
+    // A failure still reports whatever was gathered, including from the
+    // iteration that failed.
+    let mut metrics = match (config.iterations, iterations.len()) {
+        (_, 0) => Map::new(),
+        (1, _) => iterations.pop().unwrap(),
+        _ => aggregate(iterations),
+    };
+    let tagged_metrics = match (config.iterations, tagged_iterations.len()) {
+        (_, 0) => Vec::new(),
+        (1, _) => tagged_iterations.pop().unwrap(),
+        _ => aggregate_tagged(tagged_iterations),
+    };
+    if let Some(setup_wall_time) = setup_wall_time {
+        metrics.insert(
+            "setup.wall.time".into(),
+            (setup_wall_time.as_micros() as u64).into(),
+        );
+    }
+    if let Some(probes_wall_time) = probes_wall_time {
+        metrics.insert(
+            "probes.wall.time".into(),
+            (probes_wall_time.as_micros() as u64).into(),
+        );
+    }
+    metrics.extend(service_metrics);
+    if let Some((teardown_wall_time, _)) = &teardown {
+        metrics.insert(
+            "teardown.wall.time".into(),
+            (teardown_wall_time.as_micros() as u64).into(),
+        );
+    }
+
+    let mut metadata = get_metadata(opts);
+    if let Some(variant) = &config.variant {
+        metadata.insert("variant".into(), variant.clone().into());
+    }
+    metadata.insert("iterations".into(), config.iterations.into());
+    metadata.insert("warmup".into(), config.warmup.into());
+    if config.cgroup {
+        metadata.insert("cgroup".into(), cgroup_metadata.into());
+    }
+    if !statsd_complete {
+        metadata.insert("statsd_incomplete".into(), true.into());
+    }
+    let mut record = json!({
+        "metadata": metadata,
+        "status": status,
+        "units": get_units(&metrics),
+        "metrics": metrics,
+    });
+    if !tagged_metrics.is_empty() {
+        record["tagged_metrics"] = tagged_metrics.iter().map(TaggedMetric::to_json).collect();
+    }
+    if !failure.is_empty() {
+        record["failure"] = failure.into();
+    }
+    if let Some(load) = load {
+        record["load"] = load.into();
+    }
+    if !config.services.is_empty() {
+        record["services"] = service_results.into();
+    }
+    if let Some((_, teardown)) = teardown {
+        record["teardown"] = teardown.into();
+    }
+    if config.sample_interval.is_some() {
+        record["series"] = series.into();
+    }
+    record
+}
 
-    if let Some(timeout) = config.timeout {
-        task::spawn(test_timeout(timeout));
+fn load_configs(filename: &Path, opts: &LoadOpts, variant_selector: Option<&str>) -> Vec<Config> {
+    match get_configs(filename, opts, variant_selector) {
+        Ok(configs) => configs,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
     }
+}
 
-    let command = config.run[0].clone();
-    let args = config.run.iter().skip(1);
-    let status = Command::new(command)
-        .args(args)
-        .envs(&config.env)
-        .status()
-        .await;
-    if let Err(err) = status {
-        eprintln!("Error running test: {}", err);
+/// Loads the configs to run, with the overrides given on the command line.
+fn load_run_configs(opts: &RunOpts) -> Vec<Config> {
+    let filename = match &opts.config {
+        Some(filename) => filename,
+        None => {
+            eprintln!("A config file must be provided. See `sirun --help` for usage.");
+            exit(1);
+        }
+    };
+    let mut configs = load_configs(filename, &opts.load, opts.variant.as_deref());
+    for config in &mut configs {
+        if let Some(statsd_address) = &opts.statsd_address {
+            config.statsd_address = statsd_address.clone();
+        }
+        if opts.timeout.is_some() {
+            config.timeout = opts.timeout;
+        }
+    }
+    configs
+}
+
+async fn run(opts: RunOpts) {
+    if opts.dry_run {
+        return explain_configs(opts);
+    }
+    let configs = load_run_configs(&opts);
+    become_subreaper();
+    handle_interrupts();
+
+    let mut succeeded = true;
+    for config in &configs {
+        let record = run_variant(config, &opts).await;
+        if let Err(err) = emit(&record, opts.output.as_deref()) {
+            eprintln!("Error writing results: {}", err);
+            exit(1);
+        }
+        succeeded &= record["status"] == "success";
+        if let Some(signal) = interrupt_signal() {
+            // The conventional exit status for being killed by the signal.
+            exit(128 + signal);
+        }
+    }
+    if !succeeded {
         exit(1);
     }
-    let status = status.unwrap().code().unwrap();
-    if status != 0 {
-        eprintln!("Test exited with code {}, so aborting test.", status);
-        exit(status);
+}
+
+fn explain_configs(opts: RunOpts) {
+    for config in load_run_configs(&opts) {
+        let explained = explain(&config, opts.skip_setup());
+        println!("{}", serde_json::to_string_pretty(&explained).unwrap());
     }
+}
 
-    let mut metrics = HashMap::new();
-    if let Ok(hash) = env::var("GIT_COMMIT_HASH") {
-        metrics.insert("version".into(), hash);
+fn validate(opts: ConfigOpts) {
+    let selector = opts.variant.as_deref().unwrap_or("*");
+    match check_configs(&opts.config, &opts.load, Some(selector)) {
+        Ok(configs) => println!(
+            "{} is valid ({} configuration{})",
+            opts.config.display(),
+            configs.len(),
+            if configs.len() == 1 { "" } else { "s" }
+        ),
+        Err(errors) => {
+            for err in &errors {
+                eprintln!("{}", err);
+            }
+            eprintln!(
+                "{} is invalid ({} problem{})",
+                opts.config.display(),
+                errors.len(),
+                if errors.len() == 1 { "" } else { "s" }
+            );
+            exit(1);
+        }
     }
-    if let Ok(name) = env::var("SIRUN_NAME") {
-        metrics.insert("name".into(), name);
+}
+
+fn list_variants(opts: ConfigOpts) {
+    let configs = load_configs(
+        &opts.config,
+        &opts.load,
+        Some(opts.variant.as_deref().unwrap_or("*")),
+    );
+    for variant in configs.iter().filter_map(|config| config.variant.as_ref()) {
+        println!("{}", variant);
     }
-    get_kernel_metrics(&mut metrics);
-    get_statsd_metrics(&mut metrics, statsd_buf.read().await.clone());
+}
 
-    println!("results: {:?}", metrics);
+#[async_std::main]
+async fn main() {
+    let cli = Cli::from_args();
+    match cli.command {
+        Some(Subcommand::Run(opts)) => run(opts).await,
+        Some(Subcommand::Explain(opts)) => explain_configs(opts),
+        Some(Subcommand::Validate(opts)) => validate(opts),
+        Some(Subcommand::ListVariants(opts)) => list_variants(opts),
+        Some(Subcommand::Schema) => print!("{}", SCHEMA),
+        None => run(cli.run).await,
+    }
     exit(0);
 }
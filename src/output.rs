@@ -0,0 +1,137 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use nix::sys::signal::Signal;
+use serde_json::{Map, Number, Value};
+use std::{
+    convert::TryFrom,
+    fs::OpenOptions,
+    io::{self, Write},
+    os::unix::process::ExitStatusExt,
+    path::Path,
+    process::ExitStatus,
+};
+
+use crate::cli::RunOpts;
+
+/// Units for each of the metrics sirun produces itself.
+const UNITS: &[(&str, &str)] = &[
+    ("wall.time", "microseconds"),
+    ("setup.wall.time", "microseconds"),
+    ("probes.wall.time", "microseconds"),
+    ("teardown.wall.time", "microseconds"),
+    ("user.time", "microseconds"),
+    ("system.time", "microseconds"),
+    ("max.res.size", "kilobytes"),
+    ("minor.faults", "count"),
+    ("major.faults", "count"),
+    ("vol.ctx.switches", "count"),
+    ("invol.ctx.switches", "count"),
+    ("block.in", "count"),
+    ("block.out", "count"),
+    ("signals", "count"),
+    ("cgroup.cpu.usage", "microseconds"),
+    ("cgroup.cpu.user", "microseconds"),
+    ("cgroup.cpu.system", "microseconds"),
+    ("cgroup.memory.peak", "bytes"),
+    ("cgroup.memory.pgfault", "count"),
+    ("cgroup.memory.pgmajfault", "count"),
+    ("cgroup.io.rbytes", "bytes"),
+    ("cgroup.io.wbytes", "bytes"),
+    ("cgroup.io.rios", "count"),
+    ("cgroup.io.wios", "count"),
+    ("cgroup.pids.peak", "count"),
+    ("proc.samples", "count"),
+    ("proc.rss.peak", "kilobytes"),
+    ("proc.rss.mean", "kilobytes"),
+    ("proc.rss.slope", "kilobytes/second"),
+    ("proc.pss.peak", "kilobytes"),
+    ("proc.pss.mean", "kilobytes"),
+    ("proc.threads.peak", "count"),
+    ("proc.fds.peak", "count"),
+    ("proc.cpu.percent.mean", "percent"),
+    ("proc.cpu.percent.peak", "percent"),
+];
+
+/// Converts a metric value into a JSON number, keeping whole numbers as
+/// integers.
+pub(crate) fn number(value: f64) -> Value {
+    if value.fract() == 0.0 && value.abs() < i64::MAX as f64 {
+        return (value as i64).into();
+    }
+    Number::from_f64(value)
+        .map(Value::Number)
+        .unwrap_or(Value::Null)
+}
+
+/// Metrics that are prefixed with one of these followed by a name, like
+/// `service.<name>.user.time`, have the same units as without the prefix.
+const NAMED_PREFIXES: &[&str] = &["service.", "load."];
+
+/// Returns the units of whichever known metrics are present in `metrics`.
+pub(crate) fn get_units(metrics: &Map<String, Value>) -> Map<String, Value> {
+    metrics
+        .keys()
+        .filter_map(|name| {
+            let prefixed = NAMED_PREFIXES
+                .iter()
+                .find_map(|prefix| name.strip_prefix(prefix));
+            let metric = match prefixed {
+                Some(rest) => rest.split_once('.')?.1,
+                None => name,
+            };
+            let (_, unit) = UNITS.iter().find(|(known, _)| *known == metric)?;
+            Some((name.clone(), unit.to_string().into()))
+        })
+        .collect()
+}
+
+pub(crate) fn get_metadata(opts: &RunOpts) -> Map<String, Value> {
+    let mut metadata = Map::new();
+    if let Some(hash) = &opts.commit_hash {
+        metadata.insert("version".into(), hash.clone().into());
+    }
+    if let Some(name) = &opts.name {
+        metadata.insert("name".into(), name.clone().into());
+    }
+    metadata
+}
+
+/// Adds the details of an unsuccessful exit to `failure`, returning the status
+/// to report for it.
+pub(crate) fn describe_exit(status: ExitStatus, failure: &mut Map<String, Value>) -> &'static str {
+    if let Some(code) = status.code() {
+        failure.insert("exit_code".into(), code.into());
+        return "failed";
+    }
+    if let Some(signal) = status.signal() {
+        let name = match Signal::try_from(signal) {
+            Ok(signal) => signal.as_str().to_owned(),
+            Err(_) => signal.to_string(),
+        };
+        failure.insert("signal".into(), name.into());
+        failure.insert("core_dumped".into(), status.core_dumped().into());
+    }
+    "signaled"
+}
+
+/// Writes a single result record as one line of JSON. Records go to stdout
+/// unless an `output` file is given, in which case they're appended to it.
+pub(crate) fn emit(record: &Value, output: Option<&Path>) -> io::Result<()> {
+    let line = format!("{}\n", record);
+    match output {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(line.as_bytes()),
+        None => {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            stdout.write_all(line.as_bytes())?;
+            stdout.flush()
+        }
+    }
+}
@@ -0,0 +1,139 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use async_std::{
+    io::{self, prelude::*},
+    net::TcpStream,
+    task,
+};
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    os::unix::fs::FileTypeExt,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{Check, Probe},
+    output::number,
+    process::{interrupted, run_unmeasured},
+};
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Check::Tcp(address) => write!(f, "tcp {}", address),
+            Check::Http { host, path, .. } => write!(f, "http http://{}{}", host, path),
+            Check::File(path) => write!(f, "file {}", path.display()),
+            Check::Socket(path) => write!(f, "socket {}", path.display()),
+            Check::Command(command) => write!(f, "command {}", command.join(" ")),
+        }
+    }
+}
+
+async fn get_status(address: &str, host: &str, path: &str) -> io::Result<u16> {
+    let mut stream = TcpStream::connect(address).await?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    stream.write_all(request.as_bytes()).await?;
+    // Only the status line is needed, e.g. `HTTP/1.1 200 OK`.
+    let mut response = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !response.contains(&b'\n') {
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..len]);
+    }
+    String::from_utf8_lossy(&response)
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response"))
+}
+
+/// Makes a single attempt at `check`, giving up after `timeout`.
+async fn attempt(
+    check: &Check,
+    env: &HashMap<String, String>,
+    timeout: Duration,
+) -> Result<(), String> {
+    match check {
+        Check::Tcp(address) => io::timeout(timeout, TcpStream::connect(address.as_str()))
+            .await
+            .map(|_| ())
+            .map_err(|err| err.to_string()),
+        Check::Http {
+            address,
+            host,
+            path,
+            status,
+        } => match io::timeout(timeout, get_status(address, host, path)).await {
+            Ok(actual) if actual == *status => Ok(()),
+            Ok(actual) => Err(format!("got status {}, expected {}", actual, status)),
+            Err(err) => Err(err.to_string()),
+        },
+        Check::File(path) => match fs::metadata(path) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.to_string()),
+        },
+        Check::Socket(path) => match fs::metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => Ok(()),
+            Ok(_) => Err("not a socket".into()),
+            Err(err) => Err(err.to_string()),
+        },
+        Check::Command(command) => match run_unmeasured(command, env, Some(timeout), true).await {
+            Ok((Some(status), _)) if status.success() => Ok(()),
+            Ok((Some(status), _)) => Err(format!("exited with {}", status)),
+            Ok((None, _)) if interrupted() => Err("interrupted".into()),
+            Ok((None, _)) => Err("timed out".into()),
+            Err(err) => Err(err.to_string()),
+        },
+    }
+}
+
+/// Checks each probe in turn until it passes, returning how long that took
+/// altogether, or the details of the first probe that doesn't pass within its
+/// timeout.
+pub(crate) async fn wait_for_probes(
+    probes: &[Probe],
+    env: &HashMap<String, String>,
+) -> Result<Duration, Map<String, Value>> {
+    let start = Instant::now();
+    for probe in probes {
+        let probe_start = Instant::now();
+        let mut attempts: u64 = 0;
+        loop {
+            if interrupted() {
+                let mut failure = Map::new();
+                failure.insert("probe".into(), probe.check.to_string().into());
+                failure.insert("attempts".into(), attempts.into());
+                return Err(failure);
+            }
+            attempts += 1;
+            let remaining = probe.timeout.saturating_sub(probe_start.elapsed());
+            let err = match attempt(&probe.check, env, remaining).await {
+                Ok(()) => break,
+                Err(err) => err,
+            };
+            let remaining = probe.timeout.saturating_sub(probe_start.elapsed());
+            if remaining.is_zero() {
+                eprintln!("Probe {} did not pass: {}", probe.check, err);
+                let mut failure = Map::new();
+                failure.insert("probe".into(), probe.check.to_string().into());
+                failure.insert("error".into(), err.into());
+                failure.insert("attempts".into(), attempts.into());
+                failure.insert("timeout".into(), number(probe.timeout.as_secs_f64()));
+                return Err(failure);
+            }
+            task::sleep(probe.interval.min(remaining)).await;
+        }
+    }
+    Ok(start.elapsed())
+}
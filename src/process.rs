@@ -0,0 +1,429 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use async_std::{
+    future::{self, Future},
+    prelude::FutureExt,
+    task::{self, JoinHandle},
+};
+use nix::{
+    libc::{self, c_int, pid_t, rusage, wait4},
+    sys::signal::{killpg, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+    unistd::{setpgid, Pid},
+};
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    io::{self, Error, Read, Result, Write},
+    mem,
+    os::{
+        fd::AsFd,
+        unix::{
+            ffi::OsStringExt,
+            process::{CommandExt, ExitStatusExt},
+        },
+    },
+    process::{Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{cgroup::Cgroup, rusage::add_rusage};
+
+/// The outcome of a child process, along with the resources used by it and
+/// all of its descendants.
+pub(crate) struct Exit {
+    pub(crate) status: ExitStatus,
+    pub(crate) rusage: rusage,
+    pub(crate) stderr: String,
+    /// Empty unless stdout was captured.
+    pub(crate) stdout: String,
+}
+
+/// How much of the end of a command's stderr to keep.
+const STDERR_TAIL_BYTES: usize = 4096;
+
+/// How much of the end of a command's stdout to keep, when it's captured.
+const STDOUT_TAIL_BYTES: usize = 65536;
+
+/// Passes a child's output through to sirun's stderr, while keeping the last
+/// `max_bytes` of it to include in the results.
+pub(crate) struct OutputTail {
+    tail: Arc<Mutex<Vec<u8>>>,
+    done: mpsc::Receiver<()>,
+}
+
+impl OutputTail {
+    pub(crate) fn capture(mut output: impl Read + Send + 'static, max_bytes: usize) -> OutputTail {
+        let tail = Arc::new(Mutex::new(Vec::new()));
+        let (done_sender, done) = mpsc::channel();
+        let reader_tail = tail.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            loop {
+                let len = match output.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => len,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                };
+                let _ = io::stderr().write_all(&buf[..len]);
+                let mut tail = reader_tail.lock().unwrap();
+                tail.extend_from_slice(&buf[..len]);
+                let excess = tail.len().saturating_sub(max_bytes);
+                tail.drain(..excess);
+            }
+            let _ = done_sender.send(());
+        });
+        OutputTail { tail, done }
+    }
+
+    /// Returns the end of what's been written. Anything the command left
+    /// running in the background may still hold the pipe open, so this only
+    /// waits briefly for the rest.
+    pub(crate) async fn finish(self) -> String {
+        task::spawn_blocking(move || {
+            let _ = self.done.recv_timeout(Duration::from_millis(100));
+            let tail = self.tail.lock().unwrap();
+            String::from_utf8_lossy(&tail).into_owned()
+        })
+        .await
+    }
+}
+
+/// Makes sirun the parent of any orphaned descendants of the processes it
+/// runs, instead of init, so that they can be waited for and measured too.
+#[cfg(target_os = "linux")]
+pub(crate) fn become_subreaper() {
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } == -1 {
+        eprintln!(
+            "Unable to become a child subreaper: {}",
+            Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn become_subreaper() {}
+
+/// Waits for every child of sirun in the process group led by `pgid`. Since
+/// sirun is a subreaper, that includes descendants of the leader that were
+/// orphaned, so their resource usage is added to that of the leader (which
+/// already covers any descendants it waited for itself).
+fn wait_for_group(pgid: pid_t) -> Result<Exit> {
+    let mut leader_status = None;
+    let mut total: rusage = unsafe { mem::zeroed() };
+    loop {
+        let mut status: c_int = 0;
+        let mut usage: rusage = unsafe { mem::zeroed() };
+        let pid = unsafe { wait4(-pgid, &mut status, 0, &mut usage) };
+        if pid == -1 {
+            let err = Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::ECHILD) if leader_status.is_some() => break,
+                _ => return Err(err),
+            }
+        }
+        add_rusage(&mut total, &usage);
+        if pid == pgid {
+            leader_status = Some(status);
+        }
+    }
+    Ok(Exit {
+        status: ExitStatus::from_raw(leader_status.unwrap()),
+        rusage: total,
+        stderr: String::new(),
+        stdout: String::new(),
+    })
+}
+
+/// Sirun's own stderr, for a child's stdout to be written to.
+fn stderr_stdio() -> Result<Stdio> {
+    Ok(io::stderr().as_fd().try_clone_to_owned()?.into())
+}
+
+/// A running child process, which leads its own process group so that it can be
+/// signalled along with any of its descendants.
+pub(crate) struct Child {
+    pgid: Pid,
+    exit: JoinHandle<Result<Exit>>,
+    stderr: OutputTail,
+    stdout: Option<OutputTail>,
+}
+
+/// Spawns `command` in a new process group, and in `cgroup` if one is given.
+/// Unlike `getrusage` with `RUSAGE_CHILDREN`, the resource usage returned on
+/// exit covers only this child and its descendants, so it isn't polluted by
+/// anything else sirun has run. Its stdout goes to sirun's stderr, so that
+/// sirun's stdout only has results on it, and if `capture_stdout` is set its
+/// end is also included in the `Exit`.
+pub(crate) fn spawn(
+    command: &[String],
+    env: &HashMap<String, String>,
+    cgroup: Option<&Cgroup>,
+    capture_stdout: bool,
+) -> Result<Child> {
+    let procs_path = match cgroup {
+        Some(cgroup) => Some(CString::new(
+            cgroup.procs_path().into_os_string().into_vec(),
+        )?),
+        None => None,
+    };
+    let mut command_builder = Command::new(&command[0]);
+    command_builder
+        .args(&command[1..])
+        .envs(env)
+        .stderr(Stdio::piped());
+    if capture_stdout {
+        command_builder.stdout(Stdio::piped());
+    } else {
+        command_builder.stdout(stderr_stdio()?);
+    }
+    unsafe {
+        command_builder.pre_exec(move || {
+            if libc::setpgid(0, 0) == -1 {
+                return Err(Error::last_os_error());
+            }
+            // Joining the cgroup before exec means that everything the
+            // command starts is in it too.
+            if let Some(procs_path) = &procs_path {
+                let fd = libc::open(procs_path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                if fd == -1 || libc::write(fd, b"0".as_ptr().cast(), 1) != 1 {
+                    return Err(Error::last_os_error());
+                }
+                libc::close(fd);
+            }
+            Ok(())
+        });
+    }
+    let mut child = command_builder.spawn()?;
+    let stderr = OutputTail::capture(child.stderr.take().unwrap(), STDERR_TAIL_BYTES);
+    let stdout = child
+        .stdout
+        .take()
+        .map(|stdout| OutputTail::capture(stdout, STDOUT_TAIL_BYTES));
+    let pid = child.id() as pid_t;
+    // Also done here so that the group is guaranteed to exist once this
+    // returns, regardless of whether the child has got that far yet.
+    let _ = setpgid(Pid::from_raw(pid), Pid::from_raw(pid));
+    Ok(Child {
+        pgid: Pid::from_raw(pid),
+        exit: task::spawn_blocking(move || wait_for_group(pid)),
+        stderr,
+        stdout,
+    })
+}
+
+/// The signal that interrupted sirun, or 0 until it's been interrupted.
+static INTERRUPTED: AtomicI32 = AtomicI32::new(0);
+
+/// The signals that stop sirun gracefully. Since the commands it runs are in
+/// their own process groups, they'd be left running if sirun was killed by
+/// any of these.
+const INTERRUPT_SIGNALS: [Signal; 3] = [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP];
+
+/// How often to check whether sirun has been interrupted while waiting.
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+extern "C" fn on_interrupt(signal: c_int) {
+    INTERRUPTED.store(signal, Ordering::SeqCst);
+}
+
+/// Makes `SIGINT`, `SIGTERM` and `SIGHUP` stop whatever is running
+/// gracefully, rather than killing sirun outright, so that what it's running
+/// is killed, `teardown` still runs and results are still reported. The
+/// commands sirun runs are in their own process groups, so they don't get the
+/// `SIGINT` from a terminal themselves.
+pub(crate) fn handle_interrupts() {
+    let action = SigAction::new(
+        SigHandler::Handler(on_interrupt),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for signal in &INTERRUPT_SIGNALS {
+        if let Err(err) = unsafe { sigaction(*signal, &action) } {
+            eprintln!("Unable to handle {}: {}", signal.as_str(), err);
+        }
+    }
+}
+
+pub(crate) fn interrupted() -> bool {
+    interrupt_signal().is_some()
+}
+
+/// The signal that interrupted sirun, if it has been.
+pub(crate) fn interrupt_signal() -> Option<c_int> {
+    Some(INTERRUPTED.load(Ordering::SeqCst)).filter(|signal| *signal != 0)
+}
+
+/// Why a command was stopped before it exited by itself.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stop {
+    TimedOut,
+    Interrupted,
+}
+
+/// Resolves once `timeout` has passed, or as soon as sirun is interrupted if
+/// `interruptible` is set.
+async fn stop_after(timeout: Option<Duration>, interruptible: bool) -> Stop {
+    let start = Instant::now();
+    loop {
+        if interruptible && interrupted() {
+            return Stop::Interrupted;
+        }
+        let mut wait = INTERRUPT_POLL_INTERVAL;
+        if let Some(timeout) = timeout {
+            let remaining = timeout.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                return Stop::TimedOut;
+            }
+            wait = wait.min(remaining);
+        }
+        task::sleep(wait).await;
+    }
+}
+
+/// Waits for `exit` unless `stop` resolves first.
+async fn wait_unless_stopped<T>(
+    exit: &mut JoinHandle<T>,
+    stop: impl Future<Output = Stop>,
+) -> std::result::Result<T, Stop> {
+    let exited = async { Ok(exit.await) };
+    let stopped = async { Err(stop.await) };
+    exited.race(stopped).await
+}
+
+/// Runs a command that isn't being measured, such as `setup`, in its own
+/// process group, which is killed if it runs for longer than `timeout` or, if
+/// it's `interruptible`, when sirun is interrupted. Unlike with `spawn`,
+/// anything it leaves running in the background is neither waited for nor
+/// killed. Returns its exit status, or `None` if it was killed, along with the
+/// end of its stderr. Like with `spawn`, its stdout goes to sirun's stderr.
+pub(crate) async fn run_unmeasured(
+    command: &[String],
+    env: &HashMap<String, String>,
+    timeout: Option<Duration>,
+    interruptible: bool,
+) -> Result<(Option<ExitStatus>, String)> {
+    let mut command_builder = Command::new(&command[0]);
+    command_builder
+        .args(&command[1..])
+        .envs(env)
+        .stdout(stderr_stdio()?)
+        .stderr(Stdio::piped());
+    unsafe {
+        command_builder.pre_exec(|| {
+            if libc::setpgid(0, 0) == -1 {
+                return Err(Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command_builder.spawn()?;
+    let stderr = OutputTail::capture(child.stderr.take().unwrap(), STDERR_TAIL_BYTES);
+    let pgid = Pid::from_raw(child.id() as pid_t);
+    let _ = setpgid(pgid, pgid);
+    let mut exit = task::spawn_blocking(move || child.wait());
+    let status = match wait_unless_stopped(&mut exit, stop_after(timeout, interruptible)).await {
+        Ok(status) => Some(status?),
+        Err(_) => {
+            let _ = killpg(pgid, Signal::SIGKILL);
+            exit.await?;
+            None
+        }
+    };
+    Ok((status, stderr.finish().await))
+}
+
+impl Child {
+    pub(crate) fn pgid(&self) -> Pid {
+        self.pgid
+    }
+
+    fn signal(&self, signal: Signal) {
+        // The group may already be gone, which is fine.
+        let _ = killpg(self.pgid, signal);
+    }
+
+    /// Waits for the child and any of its descendants still in its process
+    /// group to exit. If they run for longer than `timeout`, or sirun is
+    /// interrupted, the whole group is sent `SIGTERM`, and then `SIGKILL` if it
+    /// still hasn't exited after `grace_period`. Any stragglers left in the
+    /// group that sirun couldn't wait for are killed too. Also returns why the
+    /// child was stopped, if it was. The end of its output is included in the
+    /// `Exit`.
+    pub(crate) async fn wait(
+        mut self,
+        timeout: Option<Duration>,
+        grace_period: Duration,
+    ) -> Result<(Exit, Option<Stop>)> {
+        let (mut exit, stop) = self.wait_for_exit(timeout, grace_period).await?;
+        self.finish_output(&mut exit).await;
+        Ok((exit, stop))
+    }
+
+    async fn wait_for_exit(
+        &mut self,
+        timeout: Option<Duration>,
+        grace_period: Duration,
+    ) -> Result<(Exit, Option<Stop>)> {
+        let stop = match wait_unless_stopped(&mut self.exit, stop_after(timeout, true)).await {
+            Ok(Ok(exit)) => return Ok((exit, None)),
+            Ok(Err(err)) => {
+                // It can't be waited for, so don't leave it running.
+                self.signal(Signal::SIGKILL);
+                return Err(err);
+            }
+            Err(stop) => stop,
+        };
+        Ok((self.terminate(grace_period).await?, Some(stop)))
+    }
+
+    /// Stops the child straight away, in the same way as `wait` does once
+    /// it's been running too long. Also returns whether it had already exited
+    /// by itself.
+    pub(crate) async fn stop(mut self, grace_period: Duration) -> Result<(Exit, bool)> {
+        // Polling the exit with no time to spare finds out whether it's
+        // already happened, without waiting for it.
+        let (mut exit, exited) = match future::timeout(Duration::ZERO, &mut self.exit).await {
+            Ok(Ok(exit)) => (exit, true),
+            Ok(Err(err)) => {
+                self.signal(Signal::SIGKILL);
+                return Err(err);
+            }
+            Err(_) => (self.terminate(grace_period).await?, false),
+        };
+        self.finish_output(&mut exit).await;
+        Ok((exit, exited))
+    }
+
+    async fn finish_output(self, exit: &mut Exit) {
+        exit.stderr = self.stderr.finish().await;
+        if let Some(stdout) = self.stdout {
+            exit.stdout = stdout.finish().await;
+        }
+    }
+
+    /// Sends the group `SIGTERM`, and then `SIGKILL` if the child still hasn't
+    /// exited after `grace_period`, followed by `SIGKILL` for any stragglers,
+    /// which is sent even if the child couldn't be waited for.
+    async fn terminate(&mut self, grace_period: Duration) -> Result<Exit> {
+        self.signal(Signal::SIGTERM);
+        let exit = match future::timeout(grace_period, &mut self.exit).await {
+            Ok(exit) => exit,
+            Err(_) => {
+                self.signal(Signal::SIGKILL);
+                (&mut self.exit).await
+            }
+        };
+        self.signal(Signal::SIGKILL);
+        exit
+    }
+}
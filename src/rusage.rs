@@ -0,0 +1,61 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use nix::libc::{rusage, timeval};
+use serde_json::{Map, Value};
+
+// `tv_usec` is only an `i64` on some platforms.
+#[allow(clippy::unnecessary_cast)]
+fn micros(time: timeval) -> i64 {
+    time.tv_sec as i64 * 1_000_000 + time.tv_usec as i64
+}
+
+// Linux reports `ru_maxrss` in kilobytes, while macOS reports it in bytes.
+#[cfg(target_os = "macos")]
+fn max_rss_kb(data: &rusage) -> Value {
+    (data.ru_maxrss / 1024).into()
+}
+
+#[cfg(not(target_os = "macos"))]
+fn max_rss_kb(data: &rusage) -> Value {
+    data.ru_maxrss.into()
+}
+
+fn add_time(total: &mut timeval, other: timeval) {
+    total.tv_sec += other.tv_sec;
+    total.tv_usec += other.tv_usec;
+    if total.tv_usec >= 1_000_000 {
+        total.tv_sec += 1;
+        total.tv_usec -= 1_000_000;
+    }
+}
+
+/// Adds the resources used by another process to `total`. Everything is summed
+/// apart from the peak memory, which is the largest of the two.
+pub(crate) fn add_rusage(total: &mut rusage, other: &rusage) {
+    add_time(&mut total.ru_utime, other.ru_utime);
+    add_time(&mut total.ru_stime, other.ru_stime);
+    total.ru_maxrss = total.ru_maxrss.max(other.ru_maxrss);
+    total.ru_minflt += other.ru_minflt;
+    total.ru_majflt += other.ru_majflt;
+    total.ru_nvcsw += other.ru_nvcsw;
+    total.ru_nivcsw += other.ru_nivcsw;
+    total.ru_inblock += other.ru_inblock;
+    total.ru_oublock += other.ru_oublock;
+    total.ru_nsignals += other.ru_nsignals;
+}
+
+pub(crate) fn get_kernel_metrics(metrics: &mut Map<String, Value>, data: &rusage) {
+    metrics.insert("user.time".into(), micros(data.ru_utime).into());
+    metrics.insert("system.time".into(), micros(data.ru_stime).into());
+    metrics.insert("max.res.size".into(), max_rss_kb(data));
+    metrics.insert("minor.faults".into(), data.ru_minflt.into());
+    metrics.insert("major.faults".into(), data.ru_majflt.into());
+    metrics.insert("vol.ctx.switches".into(), data.ru_nvcsw.into());
+    metrics.insert("invol.ctx.switches".into(), data.ru_nivcsw.into());
+    metrics.insert("block.in".into(), data.ru_inblock.into());
+    metrics.insert("block.out".into(), data.ru_oublock.into());
+    metrics.insert("signals".into(), data.ru_nsignals.into());
+}
@@ -0,0 +1,216 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use async_std::{
+    channel::{bounded, Receiver, Sender},
+    future,
+    task::{self, JoinHandle},
+};
+use nix::{libc, unistd::Pid};
+use serde_json::{json, Map, Value};
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::output::number;
+
+/// Measurements of a process group taken over time. Each sample covers every
+/// process in the group at that moment.
+#[derive(Default)]
+pub(crate) struct Series {
+    time: Vec<u64>,
+    rss: Vec<u64>,
+    pss: Vec<u64>,
+    threads: Vec<u64>,
+    fds: Vec<u64>,
+    cpu_time: Vec<u64>,
+}
+
+#[derive(Default)]
+struct Sample {
+    rss: u64,
+    pss: u64,
+    threads: u64,
+    fds: u64,
+    cpu_time: u64,
+}
+
+/// Reads the value of a `Key:   value kB` line from a `/proc` file.
+fn read_kb(contents: &str, key: &str) -> u64 {
+    contents
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+        .and_then(|value| value.split_whitespace().next()?.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Adds a single process to `sample` if it's in the process group `pgid`.
+fn add_process(sample: &mut Sample, proc_dir: &Path, pgid: i64, ticks_per_sec: u64) -> Option<()> {
+    let stat = fs::read_to_string(proc_dir.join("stat")).ok()?;
+    // The command name is in parentheses and may contain spaces, so fields
+    // are counted from the last parenthesis, starting with the state.
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    if fields.get(2)?.parse::<i64>().ok()? != pgid {
+        return None;
+    }
+    let ticks = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+    sample.cpu_time += ticks * 1_000_000 / ticks_per_sec;
+    sample.threads += fields.get(17)?.parse::<u64>().ok()?;
+
+    let status = fs::read_to_string(proc_dir.join("status")).unwrap_or_default();
+    sample.rss += read_kb(&status, "VmRSS");
+    let smaps = fs::read_to_string(proc_dir.join("smaps_rollup")).unwrap_or_default();
+    sample.pss += read_kb(&smaps, "Pss");
+    sample.fds += fs::read_dir(proc_dir.join("fd"))
+        .map(|fds| fds.count() as u64)
+        .unwrap_or(0);
+    Some(())
+}
+
+/// Measures every process in the group `pgid`, returning `None` if there
+/// aren't any.
+fn take_sample(pgid: Pid, ticks_per_sec: u64) -> Option<Sample> {
+    let mut sample = Sample::default();
+    let mut found = false;
+    for entry in fs::read_dir("/proc").ok()?.flatten() {
+        let is_pid = entry.file_name().to_string_lossy().parse::<u32>().is_ok();
+        if is_pid {
+            let pgid = pgid.as_raw().into();
+            found |= add_process(&mut sample, &entry.path(), pgid, ticks_per_sec).is_some();
+        }
+    }
+    if found {
+        Some(sample)
+    } else {
+        None
+    }
+}
+
+async fn sample_until_stopped(pgid: Pid, interval: Duration, stop: Receiver<()>) -> Series {
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+    let start = Instant::now();
+    let mut series = Series::default();
+    loop {
+        // Reading `/proc` blocks, so it's kept off the async executor.
+        let sample = task::spawn_blocking(move || take_sample(pgid, ticks_per_sec)).await;
+        if let Some(sample) = sample {
+            series.time.push(start.elapsed().as_micros() as u64);
+            series.rss.push(sample.rss);
+            series.pss.push(sample.pss);
+            series.threads.push(sample.threads);
+            series.fds.push(sample.fds);
+            series.cpu_time.push(sample.cpu_time);
+        }
+        if future::timeout(interval, stop.recv()).await.is_ok() {
+            return series;
+        }
+    }
+}
+
+/// Periodically samples `/proc` for a process group while it runs. Only Linux
+/// is supported, and elsewhere the series will be empty.
+pub(crate) struct Sampler {
+    stop: Sender<()>,
+    task: JoinHandle<Series>,
+}
+
+impl Sampler {
+    pub(crate) fn start(pgid: Pid, interval: Duration) -> Sampler {
+        let (stop, stopped) = bounded(1);
+        let task = task::spawn(sample_until_stopped(pgid, interval, stopped));
+        Sampler { stop, task }
+    }
+
+    pub(crate) async fn stop(self) -> Series {
+        let _ = self.stop.send(()).await;
+        self.task.await
+    }
+}
+
+fn peak(values: &[u64]) -> f64 {
+    values.iter().copied().max().unwrap_or(0) as f64
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// The least squares slope of `values` against `time`, per second.
+fn slope(time: &[u64], values: &[u64]) -> f64 {
+    let time: Vec<f64> = time.iter().map(|t| *t as f64 / 1_000_000.0).collect();
+    let values: Vec<f64> = values.iter().map(|v| *v as f64).collect();
+    let (mean_time, mean_value) = (mean(&time), mean(&values));
+    let mut covariance = 0.0;
+    let mut variance = 0.0;
+    for (t, v) in time.iter().zip(&values) {
+        covariance += (t - mean_time) * (v - mean_value);
+        variance += (t - mean_time).powi(2);
+    }
+    if variance == 0.0 {
+        0.0
+    } else {
+        covariance / variance
+    }
+}
+
+impl Series {
+    /// The CPU usage between each pair of consecutive samples, as a
+    /// percentage of a single CPU. Processes that exit in between take their
+    /// CPU time with them, so any drop is counted as no usage.
+    fn cpu_percent(&self) -> Vec<f64> {
+        self.time
+            .windows(2)
+            .zip(self.cpu_time.windows(2))
+            .map(|(time, cpu_time)| {
+                let elapsed = (time[1] - time[0]) as f64;
+                let used = cpu_time[1].saturating_sub(cpu_time[0]) as f64;
+                used / elapsed * 100.0
+            })
+            .collect()
+    }
+
+    /// The raw samples, as an array of values per measurement.
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "time": self.time,
+            "proc.rss": self.rss,
+            "proc.pss": self.pss,
+            "proc.threads": self.threads,
+            "proc.fds": self.fds,
+            "proc.cpu.time": self.cpu_time,
+        })
+    }
+
+    /// Adds metrics derived from the samples to `metrics`, unless there
+    /// weren't any.
+    pub(crate) fn get_metrics(&self, metrics: &mut Map<String, Value>) {
+        if self.time.is_empty() {
+            return;
+        }
+        let rss: Vec<f64> = self.rss.iter().map(|v| *v as f64).collect();
+        let pss: Vec<f64> = self.pss.iter().map(|v| *v as f64).collect();
+        let derived = [
+            ("proc.samples", self.time.len() as f64),
+            ("proc.rss.peak", peak(&self.rss)),
+            ("proc.rss.mean", mean(&rss)),
+            ("proc.rss.slope", slope(&self.time, &self.rss)),
+            ("proc.pss.peak", peak(&self.pss)),
+            ("proc.pss.mean", mean(&pss)),
+            ("proc.threads.peak", peak(&self.threads)),
+            ("proc.fds.peak", peak(&self.fds)),
+        ];
+        for (name, value) in &derived {
+            metrics.insert(name.to_string(), number(*value));
+        }
+        let cpu_percent = self.cpu_percent();
+        if !cpu_percent.is_empty() {
+            let peak = cpu_percent.iter().copied().fold(0.0, f64::max);
+            metrics.insert("proc.cpu.percent.mean".into(), number(mean(&cpu_percent)));
+            metrics.insert("proc.cpu.percent.peak".into(), number(peak));
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use serde_json::{Map, Value};
+use std::{collections::HashMap, slice, time::Instant};
+
+use crate::{
+    config::Service,
+    output::describe_exit,
+    probe::wait_for_probes,
+    process::{spawn, Child},
+    rusage::get_kernel_metrics,
+};
+
+/// A service that's been started, which is still running unless it exited by
+/// itself.
+pub(crate) struct RunningService<'a> {
+    service: &'a Service,
+    child: Child,
+    start: Instant,
+}
+
+/// Starts each service in turn, waiting for its probe to pass, if it has one,
+/// before starting the next. Returns the services that were started, along
+/// with the details of the one that couldn't be started or didn't become
+/// ready, if any. Those that were started need to be stopped either way.
+pub(crate) async fn start_services<'a>(
+    services: &'a [Service],
+    env: &HashMap<String, String>,
+) -> (Vec<RunningService<'a>>, Option<Map<String, Value>>) {
+    let mut running = Vec::new();
+    for service in services {
+        let start = Instant::now();
+        let child = match spawn(&service.command, env, None, false) {
+            Ok(child) => child,
+            Err(err) => {
+                eprintln!("Unable to start service {}: {}", service.name, err);
+                let mut failure = Map::new();
+                failure.insert("service".into(), service.name.clone().into());
+                failure.insert("error".into(), err.to_string().into());
+                return (running, Some(failure));
+            }
+        };
+        running.push(RunningService {
+            service,
+            child,
+            start,
+        });
+        if let Some(probe) = &service.probe {
+            if let Err(mut failure) = wait_for_probes(slice::from_ref(probe), env).await {
+                failure.insert("service".into(), service.name.clone().into());
+                return (running, Some(failure));
+            }
+        }
+    }
+    (running, None)
+}
+
+/// Stops the services in the reverse of the order they were started in,
+/// adding their resource usage to `metrics` as `service.<name>.*` metrics.
+/// Returns how each one ended, keyed by name, along with the details of the
+/// earliest started one that exited before it was stopped, or that couldn't
+/// be stopped, if any.
+pub(crate) async fn stop_services(
+    running: Vec<RunningService<'_>>,
+    metrics: &mut Map<String, Value>,
+) -> (Map<String, Value>, Option<Map<String, Value>>) {
+    let mut results = Map::new();
+    let mut failure = None;
+    for RunningService {
+        service,
+        child,
+        start,
+    } in running.into_iter().rev()
+    {
+        let (exit, exited) = match child.stop(service.grace_period).await {
+            Ok(result) => result,
+            Err(err) => {
+                // It's been killed, and the rest still need stopping.
+                eprintln!("Error stopping service {}: {}", service.name, err);
+                let mut result = Map::new();
+                result.insert("error".into(), err.to_string().into());
+                result.insert("status".into(), "error".into());
+                let mut service_failure = result.clone();
+                service_failure.insert("service".into(), service.name.clone().into());
+                failure = Some(service_failure);
+                results.insert(service.name.clone(), result.into());
+                continue;
+            }
+        };
+        let wall_time = start.elapsed();
+
+        let mut result = Map::new();
+        let status = if exited {
+            eprintln!("Service {} exited before it was stopped.", service.name);
+            let status = if exit.status.success() {
+                "exited"
+            } else {
+                describe_exit(exit.status, &mut result)
+            };
+            result.insert("stderr".into(), exit.stderr.into());
+            let mut service_failure = result.clone();
+            service_failure.insert("service".into(), service.name.clone().into());
+            service_failure.insert("status".into(), status.into());
+            // Stopping in reverse order means the last one seen was started
+            // earliest.
+            failure = Some(service_failure);
+            status
+        } else {
+            "stopped"
+        };
+        result.insert("status".into(), status.into());
+        results.insert(service.name.clone(), result.into());
+
+        let mut service_metrics = Map::new();
+        service_metrics.insert("wall.time".into(), (wall_time.as_micros() as u64).into());
+        get_kernel_metrics(&mut service_metrics, &exit.rusage);
+        for (name, value) in service_metrics {
+            metrics.insert(format!("service.{}.{}", service.name, name), value);
+        }
+    }
+    (results, failure)
+}
@@ -0,0 +1,88 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+use crate::statsd::TaggedMetric;
+
+/// Returns the `p`th percentile (0 to 100) of `sorted`, which must be in
+/// ascending order, interpolating linearly between the closest ranks.
+pub(crate) fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.len() == 1 {
+        return sorted[0];
+    }
+    let rank = p / 100.0 * (sorted.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+/// Summarizes a non-empty set of samples. The raw samples are included as-is.
+pub(crate) fn summarize(samples: Vec<Value>) -> Value {
+    let mut sorted: Vec<f64> = samples.iter().filter_map(Value::as_f64).collect();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let count = sorted.len() as f64;
+    let mean = sorted.iter().sum::<f64>() / count;
+    let variance = if sorted.len() > 1 {
+        sorted.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (count - 1.0)
+    } else {
+        0.0
+    };
+    json!({
+        "min": sorted[0],
+        "max": sorted[sorted.len() - 1],
+        "mean": mean,
+        "median": percentile(&sorted, 50.0),
+        "stddev": variance.sqrt(),
+        "p90": percentile(&sorted, 90.0),
+        "p95": percentile(&sorted, 95.0),
+        "p99": percentile(&sorted, 99.0),
+        "samples": samples,
+    })
+}
+
+/// Combines the metrics from each iteration into a summary per metric.
+/// Metrics with non-numeric values can't be summarized, so the last value seen
+/// is used instead.
+pub(crate) fn aggregate(iterations: Vec<Map<String, Value>>) -> Map<String, Value> {
+    let mut samples: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    let mut aggregated = Map::new();
+    for metrics in iterations {
+        for (name, value) in metrics {
+            if value.is_number() {
+                samples.entry(name).or_default().push(value);
+            } else {
+                aggregated.insert(name, value);
+            }
+        }
+    }
+    for (name, values) in samples {
+        aggregated.insert(name, summarize(values));
+    }
+    aggregated
+}
+
+/// Combines the tagged metrics from each iteration into a summary per
+/// combination of name and tags, in the same way as `aggregate`.
+pub(crate) fn aggregate_tagged(iterations: Vec<Vec<TaggedMetric>>) -> Vec<TaggedMetric> {
+    let mut samples: BTreeMap<(String, Vec<String>), Vec<Value>> = BTreeMap::new();
+    for metrics in iterations {
+        for metric in metrics {
+            samples
+                .entry((metric.name, metric.tags))
+                .or_default()
+                .push(metric.value);
+        }
+    }
+    samples
+        .into_iter()
+        .map(|((name, tags), values)| TaggedMetric {
+            name,
+            tags,
+            value: summarize(values),
+        })
+        .collect()
+}
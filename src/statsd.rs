@@ -0,0 +1,299 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use async_std::{
+    channel::{unbounded, Receiver, Sender},
+    future,
+    net::UdpSocket,
+    sync::{Arc, RwLock},
+    task::{self, JoinHandle},
+};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::{BTreeMap, HashSet},
+    convert::TryInto,
+    io::{Error, Result},
+    mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{output::number, stats::percentile};
+
+/// Sent by sirun itself, followed by a sequence number, to find out when the
+/// listener has caught up.
+const FLUSH_MARKER: &[u8] = b"\0sirun.flush";
+
+/// How long to wait for the listener to catch up before giving up on it.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Returns the address to send to for a socket bound to `addr`. A wildcard
+/// address can't be sent to, so it's replaced by loopback.
+pub(crate) fn sendable(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    addr
+}
+
+pub(crate) struct Statsd {
+    addr: SocketAddr,
+    buf: Arc<RwLock<String>>,
+    flushed: Receiver<u64>,
+    flushes: AtomicU64,
+    listener: JoinHandle<Result<()>>,
+}
+
+async fn statsd_listener(
+    socket: UdpSocket,
+    statsd_buf: Arc<RwLock<String>>,
+    flushed: Sender<u64>,
+) -> Result<()> {
+    loop {
+        let mut buf = vec![0u8; 4096];
+        let (recv, _peer) = socket.recv_from(&mut buf).await?;
+        if let Some(seq) = buf[..recv].strip_prefix(FLUSH_MARKER) {
+            if let Ok(seq) = seq.try_into() {
+                let _ = flushed.try_send(u64::from_le_bytes(seq));
+                continue;
+            }
+        }
+
+        let datum = String::from_utf8(buf[..recv].into()).unwrap_or_else(|_| String::new());
+        statsd_buf.write().await.push_str(&datum);
+    }
+}
+
+impl Statsd {
+    /// Starts listening for Statsd messages on `addr`, which may use port 0 to
+    /// have the OS pick one.
+    pub(crate) async fn start(addr: &str) -> Result<Statsd> {
+        let socket = UdpSocket::bind(addr).await?;
+        let addr = sendable(socket.local_addr()?);
+        let buf = Arc::new(RwLock::new(String::new()));
+        let (flush_sender, flushed) = unbounded();
+        let listener = task::spawn(statsd_listener(socket, buf.clone(), flush_sender));
+        Ok(Statsd {
+            addr,
+            buf,
+            flushed,
+            flushes: AtomicU64::new(0),
+            listener,
+        })
+    }
+
+    /// The address that Statsd messages should be sent to.
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Takes everything received so far. Datagrams sent by a child just before
+    /// it exits may not have been read off the socket yet, so this first waits
+    /// for the listener to see a marker sent after them. The marker is only
+    /// waited for briefly, as it can be dropped like any other datagram, so
+    /// this also returns whether it was seen, and so everything was taken.
+    pub(crate) async fn take(&self) -> (String, bool) {
+        let seq = self.flushes.fetch_add(1, Ordering::Relaxed);
+        let mut marker = FLUSH_MARKER.to_vec();
+        marker.extend_from_slice(&seq.to_le_bytes());
+        let local_addr = match self.addr {
+            SocketAddr::V4(_) => "127.0.0.1:0",
+            SocketAddr::V6(_) => "[::1]:0",
+        };
+        let flushed = async {
+            let socket = UdpSocket::bind(local_addr).await?;
+            socket.send_to(&marker, self.addr).await?;
+            // Markers from earlier flushes that timed out may still turn up.
+            while let Ok(flushed) = self.flushed.recv().await {
+                if flushed == seq {
+                    return Ok(true);
+                }
+            }
+            Ok::<_, Error>(false)
+        };
+        let complete = matches!(future::timeout(FLUSH_TIMEOUT, flushed).await, Ok(Ok(true)));
+        (mem::take(&mut *self.buf.write().await), complete)
+    }
+
+    /// Stops listening, freeing up the address.
+    pub(crate) async fn stop(self) {
+        self.listener.cancel().await;
+    }
+}
+
+enum Aggregate {
+    Counter(f64),
+    Gauge(f64),
+    Timer { values: Vec<f64>, count: f64 },
+    Set(HashSet<String>),
+}
+
+struct Datum<'a> {
+    name: &'a str,
+    value: &'a str,
+    kind: &'a str,
+    sample_rate: f64,
+    tags: Vec<&'a str>,
+}
+
+/// Metrics are aggregated separately for each combination of name and tags,
+/// with the tags sorted.
+type MetricKey = (String, Vec<String>);
+
+/// A metric that was sent with DogStatsD tags, which are kept apart from its
+/// name rather than being mixed into it.
+pub(crate) struct TaggedMetric {
+    pub(crate) name: String,
+    pub(crate) tags: Vec<String>,
+    pub(crate) value: Value,
+}
+
+impl TaggedMetric {
+    /// Converts the metric to a JSON object with its `name`, `value` and
+    /// `tags`, which are kept as they were sent, like `endpoint:/users`, as a
+    /// tag name can be given more than once.
+    pub(crate) fn to_json(&self) -> Value {
+        json!({ "name": self.name, "tags": self.tags, "value": self.value })
+    }
+}
+
+/// Parses a line in the form `name:value|type|@sample_rate|#tag:value,tag`,
+/// where everything after the value is optional.
+fn parse_datum(line: &str) -> Option<Datum<'_>> {
+    let mut fields = line.split('|');
+    let mut metric = fields.next()?.splitn(2, ':');
+    let name = metric.next().filter(|name| !name.is_empty())?;
+    let value = metric.next()?;
+    let kind = fields.next().unwrap_or("g");
+    let mut sample_rate = 1.0;
+    let mut tags = vec![];
+    for field in fields {
+        if let Some(rate) = field.strip_prefix('@') {
+            sample_rate = rate
+                .parse::<f64>()
+                .ok()
+                .filter(|rate| *rate > 0.0 && *rate <= 1.0)
+                .unwrap_or(1.0);
+        } else if let Some(tag_list) = field.strip_prefix('#') {
+            tags.extend(tag_list.split(',').filter(|tag| !tag.is_empty()));
+        }
+    }
+    tags.sort_unstable();
+    tags.dedup();
+    Some(Datum {
+        name,
+        value,
+        kind,
+        sample_rate,
+        tags,
+    })
+}
+
+fn add_datum(aggregates: &mut BTreeMap<MetricKey, Aggregate>, datum: Datum) {
+    let tags = datum.tags.iter().map(|tag| tag.to_string()).collect();
+    let key = (datum.name.to_owned(), tags);
+    if datum.kind == "s" {
+        match aggregates.get_mut(&key) {
+            Some(Aggregate::Set(values)) => {
+                values.insert(datum.value.into());
+            }
+            _ => {
+                let values = std::iter::once(datum.value.to_owned()).collect();
+                aggregates.insert(key, Aggregate::Set(values));
+            }
+        }
+        return;
+    }
+    // `nan` and `inf` parse, but can't be aggregated or reported in JSON.
+    let value: f64 = match datum.value.parse() {
+        Ok(value) if f64::is_finite(value) => value,
+        _ => return,
+    };
+    let aggregate = aggregates.get_mut(&key);
+    match (datum.kind, aggregate) {
+        ("c", Some(Aggregate::Counter(total))) => *total += value / datum.sample_rate,
+        ("c", _) => {
+            let counter = Aggregate::Counter(value / datum.sample_rate);
+            aggregates.insert(key, counter);
+        }
+        ("g", Some(Aggregate::Gauge(current))) if datum.value.starts_with(['+', '-']) => {
+            *current += value
+        }
+        ("g", _) => {
+            aggregates.insert(key, Aggregate::Gauge(value));
+        }
+        ("ms", Some(Aggregate::Timer { values, count }))
+        | ("h", Some(Aggregate::Timer { values, count }))
+        | ("d", Some(Aggregate::Timer { values, count })) => {
+            values.push(value);
+            *count += 1.0 / datum.sample_rate;
+        }
+        ("ms", _) | ("h", _) | ("d", _) => {
+            let timer = Aggregate::Timer {
+                values: vec![value],
+                count: 1.0 / datum.sample_rate,
+            };
+            aggregates.insert(key, timer);
+        }
+        _ => {}
+    }
+}
+
+/// Aggregates the received Statsd messages using the usual Statsd semantics.
+/// Counters are summed, gauges keep their last value (after applying any
+/// deltas), sets report how many unique values they saw, and timers,
+/// histograms and distributions are summarized as `<name>.count`,
+/// `<name>.min`, `<name>.max` and so on. Metrics sent with DogStatsD tags are
+/// added to `tagged` rather than `metrics`, along with their tags.
+pub(crate) fn get_statsd_metrics(
+    metrics: &mut Map<String, Value>,
+    tagged: &mut Vec<TaggedMetric>,
+    udp_data: String,
+) {
+    let mut aggregates = BTreeMap::new();
+    for datum in udp_data.lines().filter_map(|line| parse_datum(line.trim())) {
+        add_datum(&mut aggregates, datum);
+    }
+
+    for ((name, tags), aggregate) in aggregates {
+        let mut add = |name: String, value: Value| {
+            if tags.is_empty() {
+                metrics.insert(name, value);
+            } else {
+                tagged.push(TaggedMetric {
+                    name,
+                    tags: tags.clone(),
+                    value,
+                });
+            }
+        };
+        match aggregate {
+            Aggregate::Counter(total) => add(name, number(total)),
+            Aggregate::Gauge(value) => add(name, number(value)),
+            Aggregate::Set(values) => add(name, values.len().into()),
+            Aggregate::Timer { mut values, count } => {
+                values.sort_by(f64::total_cmp);
+                let mean = values.iter().sum::<f64>() / values.len() as f64;
+                let stats = [
+                    ("count", count),
+                    ("min", values[0]),
+                    ("max", values[values.len() - 1]),
+                    ("mean", mean),
+                    ("median", percentile(&values, 50.0)),
+                    ("p90", percentile(&values, 90.0)),
+                    ("p95", percentile(&values, 95.0)),
+                    ("p99", percentile(&values, 99.0)),
+                ];
+                for (stat, value) in &stats {
+                    add(format!("{}.{}", name, stat), number(*value));
+                }
+            }
+        }
+    }
+}
@@ -3,13 +3,19 @@
 //
 // This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
 
-use assert_cmd;
 use predicates::prelude::*;
 use serial_test::serial;
+use std::collections::HashMap;
+
+macro_rules! sirun {
+    () => {
+        assert_cmd::Command::cargo_bin("sirun").unwrap()
+    };
+}
 
 macro_rules! run {
     ($file:expr) => {
-        assert_cmd::Command::cargo_bin("sirun").unwrap().arg($file)
+        sirun!().arg($file)
     };
 }
 
@@ -26,7 +32,7 @@ fn simple_name() {
         .env("SIRUN_NAME", "test test")
         .assert()
         .success()
-        .stdout(predicate::str::contains("\"name\": \"test test\""));
+        .stdout(predicate::str::contains("\"name\":\"test test\""));
 }
 
 #[test]
@@ -36,7 +42,7 @@ fn simple_version() {
         .env("GIT_COMMIT_HASH", "123abc")
         .assert()
         .success()
-        .stdout(predicate::str::contains("\"version\": \"123abc\""));
+        .stdout(predicate::str::contains("\"version\":\"123abc\""));
 }
 
 #[test]
@@ -52,12 +58,12 @@ fn variants() {
         .env("SIRUN_VARIANT", "0")
         .assert()
         .success()
-        .stdout(predicate::str::contains("variant 0"));
+        .stderr(predicate::str::contains("variant 0"));
     run!("./examples/variants.json")
         .env("SIRUN_VARIANT", "1")
         .assert()
         .success()
-        .stdout(predicate::str::contains("variant 1"));
+        .stderr(predicate::str::contains("variant 1"));
 }
 
 #[test]
@@ -73,10 +79,1041 @@ fn env() {
         .env("SIRUN_VARIANT", "0")
         .assert()
         .success()
-        .stdout(predicate::str::contains("something zero"));
+        .stderr(predicate::str::contains("something zero"));
     run!("./examples/env.json")
         .env("SIRUN_VARIANT", "1")
         .assert()
         .success()
-        .stdout(predicate::str::contains("something one"));
+        .stderr(predicate::str::contains("something one"));
+}
+
+#[test]
+#[serial]
+fn command_stdout() {
+    // Both the setup and run commands print to stdout, but only the result is
+    // on sirun's.
+    let output = run!("examples/env.json")
+        .env("SIRUN_VARIANT", "0")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 1);
+    let record: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(record["metrics"]["udp.data"], 50);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr.matches("something zero").count(), 2);
+}
+
+#[test]
+#[serial]
+fn json_output() {
+    let output = run!("examples/simple.json").output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let record: serde_json::Value = serde_json::from_str(stdout.lines().last().unwrap()).unwrap();
+    assert_eq!(record["status"], "success");
+    assert_eq!(record["metrics"]["udp.data"], 50);
+    assert!(record["metrics"]["user.time"].is_number());
+    assert!(record["metadata"].is_object());
+}
+
+#[test]
+#[serial]
+fn output_file() {
+    let path = std::env::temp_dir().join(format!("sirun-output-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    run!("examples/simple.json")
+        .env("SIRUN_OUTPUT", &path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"metrics\"").not());
+    let contents = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let record: serde_json::Value = serde_json::from_str(contents.trim()).unwrap();
+    assert_eq!(record["metrics"]["udp.data"], 50);
+}
+
+#[test]
+#[serial]
+fn kernel_metrics() {
+    let output = run!("examples/cpu.json").output().unwrap();
+    assert!(output.status.success());
+    let record: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let metrics = &record["metrics"];
+    let cpu_time =
+        metrics["user.time"].as_i64().unwrap() + metrics["system.time"].as_i64().unwrap();
+    assert!(cpu_time > 1_000_000, "cpu time was {}", cpu_time);
+    for name in &[
+        "minor.faults",
+        "major.faults",
+        "vol.ctx.switches",
+        "invol.ctx.switches",
+    ] {
+        assert!(metrics[name].is_number(), "{} missing", name);
+    }
+    assert_eq!(record["units"]["user.time"], "microseconds");
+    assert_eq!(record["units"]["max.res.size"], "kilobytes");
+}
+
+#[test]
+#[serial]
+#[cfg(target_os = "linux")]
+fn orphan_kernel_metrics() {
+    let output = run!("examples/orphan-cpu.json").output().unwrap();
+    assert!(output.status.success());
+    let record: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let metrics = &record["metrics"];
+    let cpu_time =
+        metrics["user.time"].as_i64().unwrap() + metrics["system.time"].as_i64().unwrap();
+    assert!(cpu_time > 900_000, "cpu time was {}", cpu_time);
+    assert!(metrics["wall.time"].as_i64().unwrap() > 1_000_000);
+}
+
+#[test]
+fn cgroup_metrics() {
+    let output = run!("examples/cgroup.json").output().unwrap();
+    assert!(output.status.success());
+    let record = records(&output.stdout).pop().unwrap();
+    let cgroup = &record["metadata"]["cgroup"];
+    // Whether cgroups can be used depends on the host, so either outcome is
+    // fine as long as it's reported.
+    if cgroup["enabled"] == true {
+        let usage = record["metrics"]["cgroup.cpu.usage"].as_i64().unwrap();
+        assert!(usage > 900_000, "cpu usage was {}", usage);
+        assert_eq!(record["units"]["cgroup.cpu.usage"], "microseconds");
+        // The memory metrics are there whenever the controller could be
+        // enabled, and otherwise it's reported as missing.
+        let memory_missing = cgroup["missing_controllers"]
+            .as_array()
+            .is_some_and(|missing| missing.iter().any(|name| name == "memory"));
+        assert_eq!(
+            record["metrics"]["cgroup.memory.peak"].is_number(),
+            !memory_missing
+        );
+    } else {
+        assert_eq!(cgroup["enabled"], false);
+        assert!(cgroup["reason"].is_string());
+        assert!(record["metrics"]["cgroup.cpu.usage"].is_null());
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn sampling() {
+    let output = run!("examples/sampling.json").output().unwrap();
+    assert!(output.status.success());
+    let record = records(&output.stdout).pop().unwrap();
+    let metrics = &record["metrics"];
+    let samples = metrics["proc.samples"].as_u64().unwrap();
+    assert!(samples >= 5, "only {} samples", samples);
+    assert!(metrics["proc.rss.peak"].as_u64().unwrap() > 0);
+    assert!(metrics["proc.rss.slope"].is_number());
+    assert_eq!(metrics["proc.threads.peak"], 1);
+    assert_eq!(record["units"]["proc.rss.peak"], "kilobytes");
+
+    let series = record["series"].as_array().unwrap();
+    assert_eq!(series.len(), 1);
+    for name in &[
+        "time",
+        "proc.rss",
+        "proc.pss",
+        "proc.threads",
+        "proc.fds",
+        "proc.cpu.time",
+    ] {
+        let values = series[0][name].as_array().unwrap();
+        assert_eq!(values.len() as u64, samples, "wrong number of {}", name);
+    }
+}
+
+#[test]
+#[serial]
+fn wall_time() {
+    let output = run!("examples/sleep.json").output().unwrap();
+    assert!(output.status.success());
+    let record: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let metrics = &record["metrics"];
+    assert!(metrics["wall.time"].as_u64().unwrap() >= 1_000_000);
+    assert!(metrics["setup.wall.time"].as_u64().unwrap() >= 500_000);
+    assert!(metrics["user.time"].as_u64().unwrap() < 500_000);
+    assert_eq!(record["units"]["wall.time"], "microseconds");
+}
+
+#[test]
+#[serial]
+fn iterations() {
+    let output = run!("examples/iterations.json").output().unwrap();
+    assert!(output.status.success());
+    let record: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(record["metadata"]["iterations"], 3);
+    assert_eq!(record["metadata"]["warmup"], 1);
+    let metrics = &record["metrics"];
+    assert_eq!(metrics["wall.time"]["samples"].as_array().unwrap().len(), 3);
+    assert_eq!(
+        metrics["udp.data"]["samples"],
+        serde_json::json!([50, 50, 50])
+    );
+    assert_eq!(metrics["udp.data"]["mean"], 50.0);
+    assert_eq!(metrics["udp.data"]["stddev"], 0.0);
+    for stat in &["min", "max", "median", "p90", "p95", "p99"] {
+        assert!(metrics["user.time"][stat].is_number(), "{} missing", stat);
+    }
+}
+
+fn records(stdout: &[u8]) -> Vec<serde_json::Value> {
+    String::from_utf8_lossy(stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .filter(|record: &serde_json::Value| record.is_object())
+        .collect()
+}
+
+fn records_by_variant(stdout: &[u8]) -> HashMap<String, serde_json::Value> {
+    records(stdout)
+        .into_iter()
+        .map(|record| {
+            let variant = record["metadata"]["variant"].as_str().unwrap().to_owned();
+            (variant, record)
+        })
+        .collect()
+}
+
+fn variant_keys(stdout: &[u8]) -> Vec<String> {
+    records(stdout)
+        .iter()
+        .map(|record| record["metadata"]["variant"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+#[test]
+#[serial]
+fn all_variants() {
+    let output = run!("./examples/variants.json")
+        .env("SIRUN_VARIANT", "*")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(variant_keys(&output.stdout), vec!["0", "1"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("variant 0"));
+    assert!(stderr.contains("variant 1"));
+}
+
+#[test]
+#[serial]
+fn variant_patterns() {
+    let output = run!("./examples/named-variants.json")
+        .env("SIRUN_VARIANT", "fast-*")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        variant_keys(&output.stdout),
+        vec!["fast-large", "fast-small"]
+    );
+    let output = run!("./examples/named-variants.json")
+        .env("SIRUN_VARIANT", "slow-small, fast-large")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        variant_keys(&output.stdout),
+        vec!["fast-large", "slow-small"]
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("large-fast"));
+    assert!(stderr.contains("small-slow"));
+}
+
+#[test]
+#[serial]
+fn yaml() {
+    run!("examples/simple.yaml")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"udp.data\":50"));
+    run!("examples/simple.yaml")
+        .args(["--format", "json"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn toml() {
+    let output = run!("./examples/named-variants.toml")
+        .env("SIRUN_VARIANT", "*")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        variant_keys(&output.stdout),
+        vec!["fast-large", "fast-small", "slow-small"]
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("large-fast"));
+}
+
+#[test]
+fn extends() {
+    let output = run!("examples/extends/benchmark.json")
+        .env("SIRUN_VARIANT", "*")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("base setup"));
+    assert!(stderr.contains("base-shared-small-fast"));
+    assert!(stderr.contains("base-shared-small-slow"));
+    assert!(stderr.contains("base-shared-large-slow"));
+
+    run!("examples/extends/cycle-a.json")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "config files extend each other in a cycle: examples/extends/cycle-a.json -> examples/extends/cycle-b.json -> examples/extends/cycle-a.json",
+        ));
+    run!("examples/extends/variant-cycle.json")
+        .env("SIRUN_VARIANT", "a")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "variants extend each other in a cycle: a -> b -> a",
+        ));
+}
+
+#[test]
+fn config_errors() {
+    run!("examples/typo.json")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "examples/typo.json: unknown key 'timout' (at /timout)",
+        ));
+    run!("examples/typo.json")
+        .arg("--warn-unknown-keys")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "warning: examples/typo.json: unknown key 'timout'",
+        ));
+    run!("examples/wrong-type.yaml")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "examples/wrong-type.yaml:5:12: invalid type: sequence, expected a string (at /env/RETRIES)",
+        ));
+}
+
+#[test]
+#[serial]
+fn missing_variant() {
+    run!("./examples/named-variants.json")
+        .env("SIRUN_VARIANT", "fast-medium")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "variant fast-medium does not exist",
+        ));
+    run!("./examples/named-variants.json")
+        .env("SIRUN_VARIANT", "medium-*")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("no variants match"));
+    run!("./examples/named-variants.json")
+        .env_remove("SIRUN_VARIANT")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("SIRUN_VARIANT must be set"));
+}
+
+#[test]
+#[serial]
+fn matrix() {
+    let output = run!("./examples/matrix.json")
+        .env("SIRUN_VARIANT", "*")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        variant_keys(&output.stdout),
+        vec![
+            "flag=0/payload=large/runtime=v2",
+            "flag=0/payload=small/runtime=v2",
+            "flag=1/payload=large/runtime=v2",
+            "flag=1/payload=small/runtime=v2",
+            "flag=0/payload=small/runtime=v1",
+        ]
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("v2 large on"));
+    assert!(stderr.contains("v1 small off"));
+    assert!(!stderr.contains("v1 large"));
+
+    let output = run!("./examples/matrix.json")
+        .env("SIRUN_VARIANT", "flag=1/*")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(records(&output.stdout).len(), 2);
+}
+
+#[test]
+fn ephemeral_statsd() {
+    let children: Vec<_> = (0..3)
+        .map(|_| {
+            std::process::Command::new(assert_cmd::cargo::cargo_bin("sirun"))
+                .arg("examples/ephemeral-statsd.json")
+                .stdout(std::process::Stdio::piped())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    for child in children {
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(records(&output.stdout)[0]["metrics"]["udp.data"], 50);
+    }
+}
+
+#[test]
+fn statsd_address_env() {
+    let output = run!("examples/ephemeral-statsd.json")
+        .env("SIRUN_STATSD_ADDRESS", "127.0.0.1:8126")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("port 8126"));
+    assert_eq!(records(&output.stdout)[0]["metrics"]["udp.data"], 50);
+}
+
+#[test]
+fn statsd_types() {
+    let output = run!("examples/statsd-types.json").output().unwrap();
+    assert!(output.status.success());
+    let metrics = &records(&output.stdout)[0]["metrics"];
+    assert_eq!(metrics["hits"], 5);
+    assert_eq!(metrics["temp"], 12);
+    assert_eq!(metrics["latency.count"], 4);
+    assert_eq!(metrics["latency.min"], 10);
+    assert_eq!(metrics["latency.max"], 30);
+    assert_eq!(metrics["latency.mean"], 20);
+    assert_eq!(metrics["latency.median"], 20);
+    assert_eq!(metrics["users"], 2);
+}
+
+#[test]
+fn statsd_non_finite() {
+    let output = run!("examples/statsd-non-finite.json").output().unwrap();
+    assert!(output.status.success());
+    let metrics = &records(&output.stdout)[0]["metrics"];
+    assert_eq!(metrics["latency.count"], 1);
+    assert_eq!(metrics["latency.max"], 10);
+    assert_eq!(metrics["hits"], 1);
+    assert_eq!(metrics["temp"], 3);
+    assert!(metrics.get("bad").is_none());
+}
+
+#[test]
+fn statsd_tags() {
+    let output = run!("examples/statsd-tags.json").output().unwrap();
+    assert!(output.status.success());
+    let record = &records(&output.stdout)[0];
+    assert_eq!(record["metrics"]["requests"], 1);
+    assert!(record["metrics"]
+        .as_object()
+        .unwrap()
+        .keys()
+        .all(|name| !name.contains('#')));
+    let tagged = record["tagged_metrics"].as_array().unwrap();
+    let find = |name: &str, tags: serde_json::Value| {
+        tagged
+            .iter()
+            .find(|metric| metric["name"] == name && metric["tags"] == tags)
+            .map(|metric| metric["value"].clone())
+    };
+    assert_eq!(
+        find(
+            "requests",
+            serde_json::json!(["endpoint:/users", "method:get"])
+        ),
+        Some(2.into())
+    );
+    assert_eq!(
+        find("requests", serde_json::json!(["endpoint:/posts"])),
+        Some(1.into())
+    );
+    assert_eq!(
+        find("latency.max", serde_json::json!(["endpoint:/users"])),
+        Some(10.into())
+    );
+    assert_eq!(
+        find("requests", serde_json::json!(["canary"])),
+        Some(1.into())
+    );
+    assert_eq!(
+        find("requests", serde_json::json!(["env:a", "env:b"])),
+        Some(1.into())
+    );
+}
+
+#[test]
+fn help() {
+    sirun!()
+        .arg("--help")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("--variant"))
+        .stdout(predicate::str::contains("list-variants"));
+    sirun!()
+        .arg("--version")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(env!("CARGO_PKG_VERSION")));
+    sirun!()
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("USAGE"));
+}
+
+#[test]
+#[serial]
+fn run_subcommand_flags() {
+    let output = sirun!()
+        .args(["run", "--name", "flag name", "--commit-hash", "abc123"])
+        .args(["--variant", "1", "./examples/env.json"])
+        .env("SIRUN_NAME", "env name")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("something one"));
+    let record = &records(&output.stdout)[0];
+    assert_eq!(record["metadata"]["name"], "flag name");
+    assert_eq!(record["metadata"]["version"], "abc123");
+    assert_eq!(record["metadata"]["variant"], "1");
+}
+
+#[test]
+#[serial]
+fn timeout_flag() {
+    run!("examples/sleep.json")
+        .args(["--timeout", "0"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Timeout of 0 seconds exceeded"));
+}
+
+#[test]
+fn timeout_partial_results() {
+    let pid_file = std::env::temp_dir().join(format!("sirun-pids-{}", std::process::id()));
+    let output = run!("examples/timeout-partial.json")
+        .env("SIRUN_PID_FILE", &pid_file)
+        .assert()
+        .failure()
+        .get_output()
+        .stdout
+        .clone();
+    let record = records(&output).pop().unwrap();
+    assert_eq!(record["status"], "timeout");
+    assert_eq!(record["failure"]["timeout"], 1);
+    assert_eq!(record["metrics"]["udp.data"], 50);
+    assert!(record["metrics"]["wall.time"].as_u64().unwrap() >= 1_000_000);
+
+    // Both the shell and the background process it started should be gone.
+    let pids = std::fs::read_to_string(&pid_file).unwrap();
+    std::fs::remove_file(&pid_file).unwrap();
+    assert_eq!(pids.lines().count(), 2);
+    if cfg!(target_os = "linux") {
+        for pid in pids.lines() {
+            let stat = std::path::Path::new("/proc").join(pid).join("stat");
+            let killed = (0..50).any(|_| {
+                let gone = match std::fs::read_to_string(&stat) {
+                    Ok(stat) => stat.rsplit(')').next().unwrap().trim().starts_with('Z'),
+                    Err(_) => true,
+                };
+                if !gone {
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                }
+                gone
+            });
+            assert!(killed, "process {} is still running", pid);
+        }
+    }
+}
+
+#[test]
+fn failures() {
+    let output = run!("examples/failures.json")
+        .args(["--variant", "*"])
+        .assert()
+        .failure()
+        .get_output()
+        .stdout
+        .clone();
+    let records = records_by_variant(&output);
+    assert_eq!(records.len(), 4);
+
+    let exit_code = &records["exit-code"];
+    assert_eq!(exit_code["status"], "failed");
+    assert_eq!(exit_code["failure"]["exit_code"], 3);
+    assert_eq!(exit_code["failure"]["stderr"], "oops\n");
+    assert!(exit_code["metrics"]["wall.time"].is_number());
+
+    let signal = &records["signal"];
+    assert_eq!(signal["status"], "signaled");
+    assert_eq!(signal["failure"]["signal"], "SIGSEGV");
+    assert!(signal["failure"]["core_dumped"].is_boolean());
+    assert_eq!(signal["failure"]["stderr"], "dying\n");
+
+    let spawn = &records["spawn"];
+    assert_eq!(spawn["status"], "spawn_failed");
+    assert!(spawn["failure"]["error"].is_string());
+
+    let setup = &records["setup"];
+    assert_eq!(setup["status"], "setup_failed");
+    assert_eq!(setup["failure"]["attempts"], 1);
+    assert!(setup["failure"]["error"].is_string());
+}
+
+#[test]
+fn setup_retry() {
+    let marker = std::env::temp_dir().join(format!("sirun-marker-{}", std::process::id()));
+    let _ = std::fs::remove_file(&marker);
+    let output = run!("examples/setup-retry.json")
+        .args(["--variant", "*"])
+        .env("SIRUN_MARKER", &marker)
+        .assert()
+        .failure()
+        .get_output()
+        .stdout
+        .clone();
+    std::fs::remove_file(&marker).unwrap();
+    let records = records_by_variant(&output);
+
+    let attempts = &records["attempts"];
+    assert_eq!(attempts["status"], "setup_failed");
+    assert_eq!(attempts["failure"]["attempts"], 3);
+    assert_eq!(attempts["failure"]["exit_code"], 1);
+    assert_eq!(attempts["failure"]["stderr"], "not ready\n");
+
+    let attempt_timeout = &records["attempt-timeout"];
+    assert_eq!(attempt_timeout["status"], "setup_failed");
+    assert_eq!(attempt_timeout["failure"]["attempts"], 2);
+    assert_eq!(attempt_timeout["failure"]["timeout"], 0.2);
+
+    let deadline = &records["deadline"];
+    assert_eq!(deadline["status"], "setup_failed");
+    assert_eq!(deadline["failure"]["deadline"], 0.5);
+    let attempts = deadline["failure"]["attempts"].as_u64().unwrap();
+    assert!(attempts > 1 && attempts < 100, "made {} attempts", attempts);
+
+    let eventually = &records["eventually"];
+    assert_eq!(eventually["status"], "success");
+    assert!(eventually["metrics"]["setup.wall.time"].as_u64().unwrap() >= 100_000);
+}
+
+#[test]
+fn probes() {
+    use std::io::{Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let _ = stream.read(&mut [0; 1024]);
+            let _ = stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n");
+        }
+    });
+    let config = serde_json::json!({
+        "statsd_address": "127.0.0.1:0",
+        "probes": [
+            { "tcp": format!("127.0.0.1:{}", port) },
+            { "http": format!("http://127.0.0.1:{}/health", port), "status": 204 },
+            { "file": "examples/probe-failure.json" },
+            { "command": "true" },
+        ],
+        "run": "true",
+    });
+    let path = std::env::temp_dir().join(format!("sirun-probes-{}.json", std::process::id()));
+    std::fs::write(&path, config.to_string()).unwrap();
+    let output = run!(&path).output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(output.status.success());
+    let record = records(&output.stdout).pop().unwrap();
+    assert_eq!(record["status"], "success");
+    assert!(record["metrics"]["probes.wall.time"].is_number());
+}
+
+#[test]
+fn probe_failure() {
+    let output = run!("examples/probe-failure.json")
+        .assert()
+        .failure()
+        .get_output()
+        .stdout
+        .clone();
+    let record = records(&output).pop().unwrap();
+    assert_eq!(record["status"], "probe_failed");
+    assert_eq!(record["failure"]["probe"], "file /nonexistent/ready");
+    assert_eq!(record["failure"]["timeout"], 0.3);
+    assert!(record["failure"]["attempts"].as_u64().unwrap() > 1);
+    assert!(record["failure"]["error"].is_string());
+    assert!(record["metrics"]["wall.time"].is_null());
+}
+
+#[test]
+fn teardown() {
+    let dir = std::env::temp_dir().join(format!("sirun-teardown-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let output = run!("examples/teardown.json")
+        .args(["--variant", "*"])
+        .env("SIRUN_MARKER_DIR", &dir)
+        .assert()
+        .failure()
+        .get_output()
+        .stdout
+        .clone();
+    let records = records_by_variant(&output);
+    for variant in &["success", "failure", "timeout"] {
+        assert!(dir.join(variant).exists(), "no teardown for {}", variant);
+        assert_eq!(records[*variant]["teardown"]["status"], "success");
+        assert!(records[*variant]["metrics"]["teardown.wall.time"].is_number());
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(records["success"]["status"], "success");
+    assert_eq!(records["failure"]["status"], "failed");
+    assert_eq!(records["timeout"]["status"], "timeout");
+
+    let teardown_fails = &records["teardown-fails"];
+    assert_eq!(teardown_fails["status"], "success");
+    assert_eq!(teardown_fails["teardown"]["status"], "failed");
+    assert_eq!(teardown_fails["teardown"]["exit_code"], 1);
+    assert_eq!(teardown_fails["teardown"]["stderr"], "cleanup failed\n");
+}
+
+#[test]
+fn services() {
+    let dir = std::env::temp_dir().join(format!("sirun-services-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let output = run!("examples/services.json")
+        .args(["--variant", "*"])
+        .env("SIRUN_MARKER_DIR", &dir)
+        .assert()
+        .failure()
+        .get_output()
+        .stdout
+        .clone();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let records = records_by_variant(&output);
+
+    let running = &records["running"];
+    assert_eq!(running["status"], "success");
+    assert_eq!(running["services"]["busy"]["status"], "stopped");
+    assert_eq!(running["services"]["ready"]["status"], "stopped");
+    let metrics = &running["metrics"];
+    // The busy loop's CPU time is reported for the service, not the test.
+    assert!(metrics["service.busy.user.time"].as_u64().unwrap() > 200_000);
+    assert!(metrics["user.time"].as_u64().unwrap() < 200_000);
+    assert!(metrics["service.ready.wall.time"].is_number());
+    assert_eq!(running["units"]["service.busy.user.time"], "microseconds");
+
+    let exits = &records["exits"];
+    assert_eq!(exits["status"], "service_failed");
+    assert_eq!(exits["failure"]["service"], "crash");
+    assert_eq!(exits["failure"]["exit_code"], 3);
+    assert_eq!(exits["services"]["crash"]["status"], "failed");
+    assert_eq!(exits["services"]["crash"]["stderr"], "crashed\n");
+
+    let never_ready = &records["never-ready"];
+    assert_eq!(never_ready["status"], "service_failed");
+    assert_eq!(never_ready["failure"]["service"], "idle");
+    assert_eq!(never_ready["failure"]["probe"], "file /nonexistent/ready");
+    assert_eq!(never_ready["services"]["idle"]["status"], "stopped");
+    assert!(never_ready["metrics"]["wall.time"].is_null());
+}
+
+#[test]
+fn load() {
+    let dir = std::env::temp_dir().join(format!("sirun-load-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let output = run!("examples/load.json")
+        .args(["--variant", "*"])
+        .env("SIRUN_MARKER_DIR", &dir)
+        .assert()
+        .failure()
+        .get_output()
+        .stdout
+        .clone();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let records = records_by_variant(&output);
+
+    let success = &records["success"];
+    assert_eq!(success["status"], "success");
+    assert_eq!(success["load"]["client"]["status"], "success");
+    assert_eq!(success["load"]["other"]["status"], "success");
+    let metrics = &success["metrics"];
+    // The server's CPU time is reported for `run`, and the load commands'
+    // separately.
+    assert!(metrics["user.time"].as_u64().unwrap() > 200_000);
+    assert!(metrics["load.client.user.time"].as_u64().unwrap() < 200_000);
+    assert!(metrics["load.client.wall.time"].as_u64().unwrap() >= 500_000);
+    assert!(metrics["probes.wall.time"].is_number());
+    assert_eq!(metrics["load.client.requests"], 42);
+    assert_eq!(metrics["load.client.errors"], 0);
+    assert!(metrics["load.client.tool"].is_null());
+    assert_eq!(success["units"]["load.other.wall.time"], "microseconds");
+
+    let load_fails = &records["load-fails"];
+    assert_eq!(load_fails["status"], "load_failed");
+    assert_eq!(load_fails["failure"]["load"], "0");
+    assert_eq!(load_fails["failure"]["exit_code"], 4);
+    assert_eq!(load_fails["failure"]["stderr"], "bad request\n");
+    assert_eq!(load_fails["load"]["0"]["status"], "failed");
+
+    let server_exits = &records["server-exits"];
+    assert_eq!(server_exits["status"], "server_exited");
+    assert_eq!(server_exits["failure"]["exit_code"], 0);
+
+    let timeout = &records["timeout"];
+    assert_eq!(timeout["status"], "timeout");
+    assert_eq!(timeout["load"]["0"]["status"], "timeout");
+}
+
+#[test]
+fn interrupt() {
+    for (signal, code) in &[("-INT", 130), ("-TERM", 143), ("-HUP", 129)] {
+        let dir = std::env::temp_dir().join(format!("sirun-interrupt-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let start = std::time::Instant::now();
+        let child = std::process::Command::new(assert_cmd::cargo::cargo_bin("sirun"))
+            .arg("examples/interrupt.json")
+            .env("SIRUN_MARKER_DIR", &dir)
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        std::process::Command::new("kill")
+            .arg(signal)
+            .arg(child.id().to_string())
+            .status()
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        assert_eq!(output.status.code(), Some(*code), "{}", signal);
+        let record = records(&output.stdout).pop().unwrap();
+        assert_eq!(record["status"], "interrupted");
+        assert_eq!(record["teardown"]["status"], "success");
+        assert!(
+            dir.join("interrupted").exists(),
+            "no teardown for {}",
+            signal
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[test]
+fn list_variants() {
+    sirun!()
+        .args(["list-variants", "examples/named-variants.json"])
+        .assert()
+        .success()
+        .stdout("fast-large\nfast-small\nslow-small\n");
+    sirun!()
+        .args(["list-variants", "--variant", "slow-*"])
+        .arg("examples/named-variants.json")
+        .assert()
+        .success()
+        .stdout("slow-small\n");
+}
+
+#[test]
+fn validate() {
+    sirun!()
+        .args(["validate", "examples/matrix.json"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("is valid"));
+}
+
+#[test]
+fn validate_reports_every_problem() {
+    sirun!()
+        .args(["validate", "examples/invalid-variants.json"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "unknown key 'timout' (at /timout)",
+        ))
+        .stderr(predicate::str::contains(
+            "variant missing-base extends variant missing, which does not exist",
+        ))
+        .stderr(predicate::str::contains(
+            "variant missing-run: 'run' must be provided",
+        ))
+        .stderr(predicate::str::contains(
+            "'bash -c 'sleep 0.1' is not a properly formed shell command (at /variants/unterminated-quote/run)",
+        ))
+        .stderr(predicate::str::contains(
+            "invalid-variants.json:16:27: invalid type: integer `3`, expected a string (at /variants/numeric-env/env/RETRIES)",
+        ))
+        .stderr(predicate::str::contains("is invalid (5 problems)"));
+}
+
+#[test]
+#[serial]
+fn unselected_invalid_variants() {
+    // The other variants' problems don't stop this one from running.
+    let output = run!("examples/invalid-variants.json")
+        .args(["--variant", "valid", "--warn-unknown-keys"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(variant_keys(&output.stdout), vec!["valid"]);
+    sirun!()
+        .args([
+            "validate",
+            "examples/invalid-variants.json",
+            "--variant",
+            "v*",
+        ])
+        .arg("--warn-unknown-keys")
+        .assert()
+        .success();
+    sirun!()
+        .args([
+            "validate",
+            "examples/invalid-variants.json",
+            "--variant",
+            "numeric-env",
+        ])
+        .arg("--warn-unknown-keys")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "(at /variants/numeric-env/env/RETRIES)",
+        ))
+        .stderr(predicate::str::contains("is invalid (1 problem)"));
+}
+
+#[test]
+fn zero_probe_interval() {
+    sirun!()
+        .args(["validate", "examples/zero-probe-interval.json"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "expected a number of seconds greater than 0 (at /probes/0/interval)",
+        ));
+}
+
+fn example_files(dir: &std::path::Path, files: &mut Vec<std::path::PathBuf>) {
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            example_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+#[test]
+fn schema() {
+    let output = sirun!().arg("schema").output().unwrap();
+    assert!(output.status.success());
+    let schema: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let schema = jsonschema::JSONSchema::compile(&schema).unwrap();
+    let mut files = Vec::new();
+    example_files(std::path::Path::new("examples"), &mut files);
+    for path in files {
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let config: serde_json::Value = match path.extension().unwrap().to_str().unwrap() {
+            "yaml" => serde_yaml::from_str(&contents).unwrap(),
+            "toml" => toml::from_str(&contents).unwrap(),
+            _ => serde_json::from_str(&contents).unwrap(),
+        };
+        let problems: Vec<String> = match schema.validate(&config) {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors.map(|err| err.to_string()).collect(),
+        };
+        let name = path.file_name().unwrap().to_string_lossy();
+        match name.as_ref() {
+            "typo.json"
+            | "invalid-variants.json"
+            | "wrong-type.yaml"
+            | "zero-probe-interval.json" => {
+                assert!(!problems.is_empty(), "{} is allowed by the schema", name)
+            }
+            _ => assert!(problems.is_empty(), "{}: {:?}", path.display(), problems),
+        }
+    }
+}
+
+#[test]
+fn explain() {
+    let output = sirun!()
+        .args(["explain", "examples/env.json", "--variant", "*"])
+        .env("SIRUN_EXPLAIN_TEST", "from host")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let explained: Vec<serde_json::Value> = serde_json::Deserializer::from_slice(&output.stdout)
+        .into_iter()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(explained.len(), 2);
+    assert_eq!(
+        explained[0]["run"],
+        serde_json::json!([
+            "bash",
+            "-c",
+            "echo $MY_ENV && echo udp.data:50\\|g > /dev/udp/127.0.0.1/8125"
+        ])
+    );
+    assert_eq!(
+        explained[0]["env"]["MY_ENV"],
+        serde_json::json!({ "value": "something zero", "origin": "base" })
+    );
+    assert_eq!(
+        explained[1]["env"]["MY_ENV"],
+        serde_json::json!({ "value": "something one", "origin": "variant" })
+    );
+    assert_eq!(
+        explained[1]["env"]["SIRUN_EXPLAIN_TEST"],
+        serde_json::json!({ "value": "from host", "origin": "host" })
+    );
+
+    // The Statsd variables are shown as the run would export them.
+    let statsd_env = |address: &str| {
+        let output = sirun!()
+            .args(["explain", "examples/env.json", "--variant", "0"])
+            .args(["--statsd-address", address])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let explained: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        (
+            explained["env"]["SIRUN_STATSD_HOST"]["value"].clone(),
+            explained["env"]["SIRUN_STATSD_PORT"]["value"].clone(),
+        )
+    };
+    assert_eq!(
+        statsd_env("0.0.0.0:0"),
+        ("127.0.0.1".into(), "(assigned at run time)".into())
+    );
+    assert_eq!(statsd_env("[::1]:9125"), ("::1".into(), "9125".into()));
+    let (host, port) = statsd_env("localhost:9125");
+    let host: std::net::IpAddr = host.as_str().unwrap().parse().unwrap();
+    assert!(host.is_loopback());
+    assert_eq!(port, "9125");
+
+    // Nothing is run, so the setup command's output doesn't appear.
+    run!("examples/env.json")
+        .args(["--dry-run", "--variant", "1", "--timeout", "3"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"timeout\": 3"))
+        .stderr(predicate::str::contains("something one").not());
 }